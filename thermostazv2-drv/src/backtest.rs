@@ -0,0 +1,354 @@
+//! Replay a temperature trace, or a `Room`, through `Thermostazv::update`, to compare settings
+
+use crate::err::{ThermostazvError, ThermostazvResult};
+use crate::room::Room;
+use crate::thermostazv::Thermostazv;
+use crate::zone::DEFAULT_ZONE;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(clap::Args, Debug)]
+pub struct Backtest {
+    /// Thermostat config to test, as in config.toml, the one of the default zone if not given
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// CSV trace of `time,inside[,outside]` lines, RFC 3339 time and °C
+    #[arg(long)]
+    trace: Option<PathBuf>,
+
+    /// Heat the room model with the outside temperature of the trace,
+    /// instead of replaying its inside temperature. The replayed temperature does not follow
+    /// the relay of the tested settings, so the replay only reports their switches and duty.
+    #[arg(long)]
+    model: bool,
+
+    /// Simulated hours of the room model, without trace
+    #[arg(long, default_value_t = 168.0)]
+    hours: f64,
+
+    /// Seconds between two decisions
+    #[arg(long, default_value_t = 60)]
+    step: u64,
+
+    #[command(flatten)]
+    room: Room,
+}
+
+/// A line of the trace
+#[derive(Debug, PartialEq)]
+struct Sample {
+    at: DateTime<Local>,
+    inside: f64,
+    outside: Option<f64>,
+}
+
+/// Samples of a CSV trace, skipping `#` comments and a header
+fn parse(trace: &str) -> Result<Vec<Sample>, ThermostazvError> {
+    let mut samples = Vec::new();
+    let lines = trace
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    for (header, (n, line)) in lines.enumerate().map(|(i, line)| (i == 0, line)) {
+        let mut fields = line.split(',').map(str::trim);
+        let at = fields
+            .next()
+            .and_then(|at| DateTime::parse_from_rfc3339(at).ok());
+        let Some(at) = at else {
+            if header {
+                continue;
+            }
+            return Err(ThermostazvError::Trace(format!("bad time line {}", n + 1)));
+        };
+        let mut celsius = || -> Result<Option<f64>, ThermostazvError> {
+            fields
+                .next()
+                .filter(|field| !field.is_empty())
+                .map(str::parse)
+                .transpose()
+                .map_err(|e| ThermostazvError::Trace(format!("{e} line {}", n + 1)))
+        };
+        let inside = celsius()?
+            .ok_or_else(|| ThermostazvError::Trace(format!("no temperature line {}", n + 1)))?;
+        samples.push(Sample {
+            at: at.with_timezone(&Local),
+            inside,
+            outside: celsius()?,
+        });
+    }
+    if samples.windows(2).any(|w| w[1].at < w[0].at) {
+        return Err(ThermostazvError::Trace("time goes backwards".to_string()));
+    }
+    Ok(samples)
+}
+
+/// The last sample at `at`
+fn sample(trace: &[Sample], at: DateTime<Local>) -> Option<&Sample> {
+    trace[..trace.partition_point(|s| s.at <= at)].last()
+}
+
+/// Where the room temperature comes from
+enum Plant<'a> {
+    /// the recorded one, whatever the relay does
+    Replay(&'a [Sample]),
+    /// the room model, outside the trace one if any
+    Model(Room, &'a [Sample]),
+}
+
+impl Plant<'_> {
+    /// Room temperature at `at`, after `dt` with the relay `hot`
+    fn advance(&mut self, at: DateTime<Local>, hot: bool, dt: Duration) -> Option<f64> {
+        match *self {
+            Self::Replay(trace) => sample(trace, at).map(|s| s.inside),
+            Self::Model(ref mut room, trace) => {
+                if let Some(outside) = sample(trace, at).and_then(|s| s.outside) {
+                    room.outside = outside;
+                }
+                room.step(hot, dt);
+                Some(room.inside)
+            }
+        }
+    }
+}
+
+/// How close the room stayed to the target
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Comfort {
+    /// mean distance to the target, in °C
+    pub deviation: f64,
+    /// root mean square of the distance to the target, in °C
+    pub rms: f64,
+    /// degree hours under the target, in °C·h
+    pub under: f64,
+    /// degree hours over the target, in °C·h
+    pub over: f64,
+}
+
+/// What the settings did to the room
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Report {
+    /// in hours
+    pub duration: f64,
+    /// with the room model only, a replayed temperature owes nothing to the settings
+    #[serde(flatten)]
+    pub comfort: Option<Comfort>,
+    /// time with the relay on, in hours
+    pub on: f64,
+    /// share of the time with the relay on
+    pub duty: f64,
+    pub switches: u32,
+    /// switches refused by the short cycle protection
+    pub suppressed: u32,
+}
+
+/// Run `thermostazv` on `plant` from `start` to `end`, deciding every `step`
+/// and ending its manual override and away mode on time, as `TManager` does
+fn run(
+    thermostazv: &mut Thermostazv,
+    mut plant: Plant,
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+    step: chrono::Duration,
+) -> Report {
+    let sources: Vec<String> = thermostazv.sources.iter().map(|s| s.name.clone()).collect();
+    let dt = step.to_std().unwrap_or_default();
+    let hours = dt.as_secs_f64() / 3600.0;
+    let model = matches!(plant, Plant::Model(..));
+    let mut report = Report::default();
+    let mut comfort = Comfort::default();
+    let (mut known, mut squares) = (0.0, 0.0);
+    thermostazv.started = Some(start);
+    let mut now = start;
+    let mut inside = plant.advance(now, thermostazv.thermostat.hot, Duration::ZERO);
+    while now < end {
+        if let Some(inside) = inside {
+            for source in &sources {
+                thermostazv.read(source, inside, now);
+            }
+        }
+        thermostazv.check_return(now);
+        thermostazv.check_manual(now);
+        if thermostazv.update(now) {
+            report.switches += 1;
+        }
+        let hot = thermostazv.thermostat.hot;
+        if let Some(inside) = inside {
+            let error = inside - thermostazv.target(now);
+            known += hours;
+            comfort.deviation = error.abs().mul_add(hours, comfort.deviation);
+            squares = (error * error).mul_add(hours, squares);
+            comfort.under = (-error).max(0.0).mul_add(hours, comfort.under);
+            comfort.over = error.max(0.0).mul_add(hours, comfort.over);
+        }
+        report.duration += hours;
+        if hot {
+            report.on += hours;
+        }
+        now += step;
+        inside = plant.advance(now, hot, dt);
+    }
+    if model && known > 0.0 {
+        comfort.deviation /= known;
+        comfort.rms = (squares / known).sqrt();
+        report.comfort = Some(comfort);
+    }
+    if report.duration > 0.0 {
+        report.duty = report.on / report.duration;
+    }
+    report.suppressed = thermostazv.guard.suppressed;
+    report
+}
+
+/// Print the `Report` of the config on the trace or the room model
+pub fn backtest(args: Backtest) -> ThermostazvResult {
+    let mut thermostazv = match args.config {
+        Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+        None => Thermostazv::new(DEFAULT_ZONE)?,
+    };
+    let trace = match args.trace {
+        Some(path) => parse(&fs::read_to_string(path)?)?,
+        None => Vec::new(),
+    };
+    let (start, end) = if let (Some(first), Some(last)) = (trace.first(), trace.last()) {
+        (first.at, last.at)
+    } else {
+        let start = Local::now();
+        let end = Duration::try_from_secs_f64(args.hours.max(0.0) * 3600.0)
+            .ok()
+            .and_then(|hours| chrono::Duration::from_std(hours).ok())
+            .and_then(|hours| start.checked_add_signed(hours))
+            .ok_or_else(|| ThermostazvError::Argument(format!("{} hours", args.hours)))?;
+        (start, end)
+    };
+    let plant = if args.model || trace.is_empty() {
+        let mut room = args.room;
+        if let Some(first) = trace.first() {
+            room.inside = first.inside;
+        }
+        Plant::Model(room, &trace)
+    } else {
+        Plant::Replay(&trace)
+    };
+    let step = chrono::Duration::seconds(i64::try_from(args.step.max(1))?);
+    let report = run(&mut thermostazv, plant, start, end, step);
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thermostazv::{Manual, ManualMode};
+    use thermostazv2_lib::control::Cycling;
+
+    const TRACE: &str = "time,inside,outside\n\
+        2023-01-09T06:00:00+01:00,16.0,2.0\n\
+        # the heater was off\n\
+        2023-01-09T07:00:00+01:00,15.5,\n\
+        \n\
+        2023-01-09T08:00:00+01:00,18.0,3.5\n";
+
+    #[test]
+    fn trace() {
+        let trace = parse(TRACE).unwrap_or_default();
+        assert_eq!(trace.len(), 3);
+        assert!((trace[1].inside - 15.5).abs() < f64::EPSILON);
+        assert_eq!(trace[1].outside, None);
+        assert_eq!(trace[2].outside, Some(3.5));
+        let at = trace[0].at + chrono::Duration::minutes(90);
+        assert_eq!(sample(&trace, at), Some(&trace[1]));
+        assert_eq!(
+            sample(&trace, trace[0].at - chrono::Duration::seconds(1)),
+            None
+        );
+        assert!(parse("2023-01-09T06:00:00+01:00,16.0\nyesterday,15.0\n").is_err());
+        assert!(parse("2023-01-09T06:00:00+01:00\n").is_err());
+        assert!(parse("2023-01-09T06:00:00+01:00,hot\n").is_err());
+    }
+
+    #[test]
+    fn replay() {
+        let trace = parse(TRACE).unwrap_or_default();
+        let mut thermostazv = Thermostazv::default();
+        let start = trace[0].at;
+        let report = run(
+            &mut thermostazv,
+            Plant::Replay(&trace),
+            start,
+            start + chrono::Duration::hours(2),
+            chrono::Duration::minutes(10),
+        );
+        assert!((report.duration - 2.0).abs() < 1e-9, "{report:?}");
+        assert!(report.on <= report.duration);
+        assert_eq!(report.comfort, None);
+        let json = serde_json::to_value(&report).unwrap_or_default();
+        assert!(
+            json.get("duty").is_some() && json.get("rms").is_none(),
+            "{json}"
+        );
+    }
+
+    #[test]
+    fn tune() {
+        let room = Room {
+            inside: 15.0,
+            outside: 5.0,
+            tau: 10.0,
+            power: 2.0,
+            lag: 0.25,
+            heat: 0.0,
+        };
+        let start = Local::now();
+        let end = start + chrono::Duration::days(2);
+        let step = chrono::Duration::minutes(1);
+        let tune = |band| {
+            let mut thermostazv = Thermostazv::default();
+            thermostazv.band = band;
+            thermostazv.cycling = Cycling {
+                min_on: 0,
+                min_off: 0,
+                max_per_hour: 0,
+            };
+            run(&mut thermostazv, Plant::Model(room, &[]), start, end, step)
+        };
+        let rms = |report: &Report| report.comfort.as_ref().map_or(f64::INFINITY, |c| c.rms);
+        let wide = tune(1.0);
+        assert!(wide.on > 0.0 && wide.duty < 1.0, "{wide:?}");
+        assert!(
+            matches!(wide.comfort, Some(ref c) if c.deviation < 1.5),
+            "{wide:?}"
+        );
+        // a narrow band holds the target closer, switching more
+        let narrow = tune(0.1);
+        assert!(narrow.switches > wide.switches, "{narrow:?} {wide:?}");
+        assert!(rms(&narrow) < rms(&wide), "{narrow:?} {wide:?}");
+    }
+
+    #[test]
+    fn expired_boost() {
+        // warm enough without heating
+        let room = Room {
+            inside: 25.0,
+            outside: 20.0,
+            tau: 10.0,
+            power: 2.0,
+            lag: 0.25,
+            heat: 0.0,
+        };
+        let start = Local::now();
+        let mut thermostazv = Thermostazv::default();
+        thermostazv.manual = Some(Manual {
+            mode: ManualMode::Boost,
+            until: start + chrono::Duration::hours(1),
+        });
+        let end = start + chrono::Duration::hours(6);
+        let step = chrono::Duration::minutes(1);
+        let report = run(&mut thermostazv, Plant::Model(room, &[]), start, end, step);
+        assert_eq!(thermostazv.manual, None);
+        assert!((report.on - 1.0).abs() < 0.1, "{report:?}");
+    }
+}
@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+/// Name of the firmware AHT20 sensor
+pub const AHT20: &str = "aht20";
+
+/// Name of the Tasmota SI7021 sensor
+pub const SI7021: &str = "si7021";
+
+/// Default of the scales and the weights
+pub const fn one() -> f64 {
+    1.0
+}
+
+/// Linear correction of a reading: `scale * raw + offset`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Calibration {
+    #[serde(default)]
+    pub offset: f64,
+    #[serde(default = "one")]
+    pub scale: f64,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            offset: 0.0,
+            scale: 1.0,
+        }
+    }
+}
+
+impl Calibration {
+    pub fn apply(&self, raw: f64) -> f64 {
+        self.scale.mul_add(raw, self.offset)
+    }
+
+    /// The raw reading which would be corrected to `value`
+    pub fn invert(&self, value: f64) -> f64 {
+        (value - self.offset) / self.scale
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.offset.is_finite() && self.scale.is_normal()
+    }
+}
+
+/// Corrections of a temperature and humidity sensor
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Sensor {
+    #[serde(default)]
+    pub temperature: Calibration,
+    #[serde(default)]
+    pub humidity: Calibration,
+}
+
+impl Sensor {
+    pub fn apply(&self, celsius: f64, rh: f64) -> (f64, f64) {
+        (self.temperature.apply(celsius), self.humidity.apply(rh))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear() {
+        let calibration = Calibration {
+            offset: -0.8,
+            scale: 1.02,
+        };
+        let value = calibration.apply(20.0);
+        assert!((value - 19.6).abs() < 1e-9);
+        assert!((calibration.invert(value) - 20.0).abs() < 1e-9);
+        assert!(calibration.is_valid());
+        assert!(!Calibration {
+            offset: 0.0,
+            scale: 0.0
+        }
+        .is_valid());
+        let parsed: Result<Calibration, _> = toml::from_str("offset = 0.5");
+        assert_eq!(
+            parsed.ok(),
+            Some(Calibration {
+                offset: 0.5,
+                scale: 1.0
+            })
+        );
+    }
+}
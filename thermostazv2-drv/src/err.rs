@@ -1,4 +1,4 @@
-use thermostazv2_lib::TError;
+use thermostazv2_lib::{TError, PROTOCOL_MIN_VERSION, PROTOCOL_VERSION};
 
 pub type ThermostazvResult = anyhow::Result<()>;
 
@@ -15,4 +15,31 @@ pub enum ThermostazvError {
 
     #[error("Thermostazv lib error: {0}")]
     TError(#[from] TError),
+
+    #[error("Incompatible firmware protocol {0}, supported: {PROTOCOL_MIN_VERSION}..={PROTOCOL_VERSION}")]
+    Protocol(u16),
+
+    #[error("Timeout: {0}")]
+    Timeout(#[from] tokio::time::error::Elapsed),
+
+    #[error("Serial connection closed")]
+    Closed,
+
+    #[error("Serial port error: {0}")]
+    Serial(#[from] tokio_serial::Error),
+
+    #[error("No board found{0}")]
+    NotFound(String),
+
+    #[error("Invalid command: {0}")]
+    Invalid(String),
+
+    #[error("Invalid zone: {0}")]
+    Zone(String),
+
+    #[error("Invalid trace: {0}")]
+    Trace(String),
+
+    #[error("Invalid argument: {0}")]
+    Argument(String),
 }
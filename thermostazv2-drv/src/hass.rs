@@ -0,0 +1,180 @@
+//! Home Assistant MQTT discovery
+
+use crate::err::ThermostazvResult;
+use crate::thermostazv::SETPOINTS;
+use crate::zone::{topic, DEFAULT_ZONE};
+use rumqttc::{AsyncClient, QoS};
+use serde_json::{json, Value};
+
+/// Topics of a zone, under `/azv/{zone}`
+pub const STATE: &str = "state";
+pub const AVAILABILITY: &str = "availability";
+pub const TARGET: &str = "hass/target";
+pub const PRESET: &str = "hass/preset";
+
+/// Last will of the daemon, all the zones go unavailable with it,
+/// on the topic of the single board daemon for those who still follow it
+pub const DAEMON_AVAILABILITY: &str = "/azv/thermostazv/lwt";
+
+const PREFIX: &str = "homeassistant";
+
+fn device(zone: &str) -> Value {
+    json!({
+        "identifiers": [zone],
+        "name": if zone == DEFAULT_ZONE {
+            "Thermostazv".to_string()
+        } else {
+            format!("Thermostazv {zone}")
+        },
+        "manufacturer": "azv",
+        "model": "thermostazv2",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    })
+}
+
+/// Component, object id and config of each entity of `zone`
+fn configs(zone: &str) -> [(&'static str, &'static str, Value); 4] {
+    let state_topic = topic(zone, STATE);
+    let room = if zone == DEFAULT_ZONE { "Garage" } else { zone };
+    let availability = |topic: &str| {
+        json!({
+            "topic": topic,
+            "payload_available": "Online",
+            "payload_not_available": "Offline",
+        })
+    };
+    let common = |object: &str| {
+        json!({
+            "unique_id": format!("{zone}_{object}"),
+            "device": device(zone),
+            "availability": [
+                availability(&topic(zone, AVAILABILITY)),
+                availability(DAEMON_AVAILABILITY),
+            ],
+            "availability_mode": "all",
+            "state_topic": state_topic,
+        })
+    };
+    let with = |mut base: Value, extra: Value| {
+        if let (Some(base), Value::Object(extra)) = (base.as_object_mut(), extra) {
+            base.extend(extra);
+        }
+        base
+    };
+    [
+        (
+            "climate",
+            "thermostat",
+            with(
+                common("thermostat"),
+                json!({
+                    "name": "Thermostat",
+                    "modes": ["heat"],
+                    "mode_state_topic": state_topic,
+                    "mode_state_template": "heat",
+                    "action_topic": state_topic,
+                    "action_template": "{{ 'heating' if value_json.relay else 'idle' }}",
+                    "current_temperature_topic": state_topic,
+                    "current_temperature_template": "{{ value_json.current }}",
+                    "temperature_state_topic": state_topic,
+                    "temperature_state_template": "{{ value_json.target }}",
+                    "temperature_command_topic": topic(zone, &format!("{TARGET}/set")),
+                    "preset_modes": ["home", "away"],
+                    "preset_mode_state_topic": state_topic,
+                    "preset_mode_value_template":
+                        "{{ 'home' if value_json.present else 'away' }}",
+                    "preset_mode_command_topic": topic(zone, &format!("{PRESET}/set")),
+                    "min_temp": SETPOINTS.start(),
+                    "max_temp": SETPOINTS.end(),
+                    "temp_step": 0.5,
+                    "temperature_unit": "C",
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "temperature",
+            with(
+                common("temperature"),
+                json!({
+                    "name": format!("{room} temperature"),
+                    "device_class": "temperature",
+                    "state_class": "measurement",
+                    "unit_of_measurement": "°C",
+                    "value_template": "{{ value_json.temperature }}",
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "humidity",
+            with(
+                common("humidity"),
+                json!({
+                    "name": format!("{room} humidity"),
+                    "device_class": "humidity",
+                    "state_class": "measurement",
+                    "unit_of_measurement": "%",
+                    "value_template": "{{ value_json.humidity }}",
+                }),
+            ),
+        ),
+        (
+            "binary_sensor",
+            "relay",
+            with(
+                common("relay"),
+                json!({
+                    "name": "Heater",
+                    "device_class": "heat",
+                    "value_template": "{{ 'ON' if value_json.relay else 'OFF' }}",
+                }),
+            ),
+        ),
+    ]
+}
+
+/// Publish the retained discovery configs of `zone`
+pub async fn discovery(client: &AsyncClient, zone: &str) -> ThermostazvResult {
+    for (component, object, config) in configs(zone) {
+        client
+            .publish(
+                format!("{PREFIX}/{component}/{zone}/{object}/config"),
+                QoS::AtLeastOnce,
+                true,
+                serde_json::to_vec(&config)?,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configs_share_the_state_topic() {
+        for (_, object, config) in configs(DEFAULT_ZONE) {
+            assert_eq!(config["state_topic"], "/azv/thermostazv/state", "{object}");
+            assert_eq!(config["unique_id"], format!("thermostazv_{object}"));
+            assert_eq!(config["device"]["identifiers"][0], DEFAULT_ZONE);
+        }
+    }
+
+    #[test]
+    fn configs_of_a_zone() {
+        let [climate, temperature, ..] = configs("salon");
+        assert_eq!(climate.2["unique_id"], "salon_thermostat");
+        assert_eq!(
+            climate.2["temperature_command_topic"],
+            "/azv/salon/hass/target/set"
+        );
+        assert_eq!(
+            climate.2["availability"][0]["topic"],
+            "/azv/salon/availability"
+        );
+        assert_eq!(climate.2["availability"][1]["topic"], DAEMON_AVAILABILITY);
+        assert_eq!(temperature.2["name"], "salon temperature");
+    }
+}
@@ -1,36 +1,59 @@
-use anyhow::Context;
 use async_channel::unbounded;
-use clap::Parser;
+use clap::{ArgAction, Parser, Subcommand};
 use futures::future::try_join_all;
-use futures::stream::StreamExt;
 use rumqttc::{AsyncClient, LastWill, MqttOptions, QoS};
 use std::str::FromStr;
 use std::time::Duration;
-use thermostazv2_lib::{Cmd, Relay, SensorErr, SensorResult};
 use tokio::task;
 use tokio::time::sleep;
-use tokio_serial::SerialPortBuilderExt;
-use tokio_util::codec::Decoder;
 use tracing::Level;
 
+mod backtest;
+mod calibration;
 mod err;
+mod hass;
+mod room;
 mod sercon;
+mod sim;
+mod sources;
+mod state;
 mod status;
 mod tasks;
 mod thermostazv;
+mod zone;
 use crate::err::ThermostazvResult;
-use crate::sercon::SerialConnection;
-use crate::status::smanager;
-use crate::tasks::{
-    influx, main_task, mqtt_connection, mqtt_publish, mqtt_receive, serial_reader, serial_writer,
-};
-use crate::thermostazv::{TManager, Thermostazv};
+use crate::hass::DAEMON_AVAILABILITY;
+use crate::tasks::{main_task, mqtt_connection};
+use crate::zone::{supervise, Settings, Zone, DEFAULT_ZONE};
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Pretend to be a board on a pseudo-terminal, heating a simulated room
+    Simulate(sim::Simulate),
+    /// Report the comfort, relay-on time and switches of a config on a trace or a room model,
+    /// use --log-level warn to keep only the report
+    Backtest(backtest::Backtest),
+}
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(long, env = "UART_PORT", default_value = "/dev/thermostazv2")]
-    uart_port: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Heating zone `name`, `name=SERIAL` or `name=/dev/ttyACM0`, once per board,
+    /// configured in `name.toml` and driven under /azv/name
+    #[arg(long = "zone", env = "ZONES", value_delimiter = ',')]
+    zones: Vec<Zone>,
+
+    /// Serial port of the board, found by its USB IDs with the `udev` feature if not given,
+    /// without zones
+    #[arg(long, env = "UART_PORT")]
+    uart_port: Option<String>,
+
+    /// USB serial number of the board to find, when several are plugged, without zones
+    #[arg(long, env = "UART_SERIAL")]
+    uart_serial: Option<String>,
 
     #[arg(long, env = "UART_BAUD", default_value_t = 2_000_000)]
     uart_baud: u32,
@@ -44,8 +67,9 @@ struct Args {
     #[arg(long, env = "MQTT_USER", default_value = "nim")]
     mqtt_user: String,
 
-    #[arg(long, env = "MQTT_PASS")]
-    mqtt_pass: String,
+    /// Required to run the daemon
+    #[arg(long, env = "MQTT_PASS", required = true)]
+    mqtt_pass: Option<String>,
 
     #[arg(long, env = "INFL_BUCK", default_value = "azviot")]
     infl_buck: String,
@@ -56,11 +80,24 @@ struct Args {
     #[arg(long, env = "INFL_URL", default_value = "http://localhost:8086")]
     infl_url: String,
 
-    #[arg(long, env = "INFL_TOKEN")]
-    infl_token: String,
+    /// Required to run the daemon
+    #[arg(long, env = "INFL_TOKEN", required = true)]
+    infl_token: Option<String>,
 
     #[arg(long, env = "LOG_LEVEL", default_value = "info")]
     log_level: String,
+
+    /// Seconds without news from us before the firmware takes over the relay, 0 to disable
+    #[arg(long, env = "FAILSAFE_TIMEOUT", default_value_t = 60)]
+    failsafe_timeout: u16,
+
+    /// Temperature the firmware keeps in failsafe, at least the frost protection floor
+    #[arg(long, env = "FAILSAFE_TARGET")]
+    failsafe_target: Option<f64>,
+
+    /// Also publish human readable messages on /azv/{zone}/log
+    #[arg(long, env = "TEXT_LOG", default_value_t = true, action = ArgAction::Set)]
+    text_log: bool,
 }
 
 #[allow(clippy::too_many_lines)]
@@ -73,119 +110,68 @@ async fn main() -> ThermostazvResult {
         .finish();
     tracing::subscriber::set_global_default(subscriber)?;
 
-    let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
-
-    let thermostazv = Thermostazv::new()?;
-    let (thermostazv_cmd_send, thermostazv_cmd_receive) = async_channel::unbounded();
-    let (thermostazv_watch_send, thermostazv_watch_receive) =
-        tokio::sync::watch::channel(thermostazv.clone());
-
-    let status = Cmd::Status(Relay::Cold, SensorResult::Err(SensorErr::Uninitialized));
-    let (status_cmd_send, status_cmd_receive) = async_channel::unbounded();
-    let (status_watch_send, status_watch_receive) = tokio::sync::watch::channel(status);
-
-    let mut uart_port = tokio_serial::new(args.uart_port, args.uart_baud)
-        .open_native_async()
-        .context("Failed to open serial port")?;
-    uart_port.set_exclusive(false)?;
-
-    let (uart_writer, uart_reader) = SerialConnection::new().framed(uart_port).split();
-
-    let (to_uart_send, to_uart_receive) = unbounded();
-    let (to_mqtt_send, to_mqtt_receive) = unbounded();
-    let (from_mqtt_send, from_mqtt_receive) = unbounded();
-    let to_uart_send2 = to_uart_send.clone();
-    let to_uart_send3 = to_uart_send.clone();
-    let to_mqtt_send2 = to_mqtt_send.clone();
-
-    let lwt = LastWill::new("/azv/thermostazv/lwt", "Offline", QoS::AtLeastOnce, false);
+    match args.command {
+        Some(Command::Simulate(simulate)) => return sim::simulate(simulate).await,
+        Some(Command::Backtest(backtest)) => return backtest::backtest(backtest),
+        None => {}
+    }
+
+    let zones = if args.zones.is_empty() {
+        vec![Zone {
+            name: DEFAULT_ZONE.to_string(),
+            port: args.uart_port,
+            serial: args.uart_serial,
+        }]
+    } else {
+        if args.uart_port.is_some() || args.uart_serial.is_some() {
+            tracing::warn!("--uart-port and --uart-serial are ignored with --zone");
+        }
+        args.zones
+    };
+    zone::check(&zones)?;
+    let settings = Settings {
+        baud: args.uart_baud,
+        failsafe_timeout: args.failsafe_timeout,
+        failsafe_target: args.failsafe_target,
+        text_log: args.text_log,
+        infl_buck: args.infl_buck,
+    };
+
+    let (shutdown_sender, _) = tokio::sync::watch::channel(false);
+
+    let lwt = LastWill::new(DAEMON_AVAILABILITY, "Offline", QoS::AtLeastOnce, true);
 
     let mut mqttoptions = MqttOptions::new("thermostazv2", args.mqtt_host, args.mqtt_port);
     mqttoptions.set_keep_alive(Duration::from_secs(5));
     mqttoptions.set_last_will(lwt);
-    mqttoptions.set_credentials(args.mqtt_user, args.mqtt_pass);
+    mqttoptions.set_credentials(args.mqtt_user, args.mqtt_pass.unwrap_or_default());
 
     let (client, connection) = AsyncClient::new(mqttoptions, 10);
-
-    let mut tasks = Vec::new();
-
-    tasks.push(task::spawn(serial_writer(
-        to_uart_receive,
-        uart_writer,
-        shutdown_receiver,
-    )));
-    let shutdown_receiver = shutdown_sender.subscribe();
-    tasks.push(task::spawn(serial_reader(
-        uart_reader,
-        to_uart_send,
-        status_cmd_send,
-        to_mqtt_send,
-        shutdown_receiver,
-    )));
-
-    // mqtt receiver task
-    let shutdown_receiver = shutdown_sender.subscribe();
-    tasks.push(task::spawn(mqtt_receive(
-        to_uart_send2,
-        from_mqtt_receive,
-        thermostazv_cmd_send,
-        status_watch_receive,
-        to_mqtt_send2,
-        shutdown_receiver,
-    )));
-
-    client
-        .subscribe("/azv/thermostazv/cmd", QoS::AtMostOnce)
-        .await?;
     client
-        .subscribe("/azv/thermostazv/presence", QoS::AtMostOnce)
-        .await?;
-    client
-        .subscribe("tele/tasmota_43D8FD/SENSOR", QoS::AtMostOnce)
-        .await?;
-    client
-        .publish("/azv/thermostazv/log", QoS::AtLeastOnce, false, "Hi !")
+        .publish(DAEMON_AVAILABILITY, QoS::AtLeastOnce, true, "Online")
         .await?;
 
-    // mqtt publisher task
-    let shutdown_receiver = shutdown_sender.subscribe();
-    tasks.push(task::spawn(mqtt_publish(
-        to_mqtt_receive,
-        thermostazv_watch_receive,
-        client,
-        shutdown_receiver,
-    )));
+    let influx_client = influxdb2::Client::new(
+        args.infl_url,
+        args.infl_org,
+        args.infl_token.unwrap_or_default(),
+    );
 
-    let client = influxdb2::Client::new(args.infl_url, args.infl_org, args.infl_token);
-    let thermostazv_watch_receive = thermostazv_watch_send.subscribe();
-    let status_watch_receive = status_watch_send.subscribe();
-    let shutdown_receiver = shutdown_sender.subscribe();
-    tasks.push(task::spawn(async move {
-        influx(
-            client,
-            thermostazv_watch_receive,
-            status_watch_receive,
-            &args.infl_buck,
-            shutdown_receiver,
-        )
-        .await
-    }));
+    let mut tasks = Vec::new();
+    let mut from_mqtt_send = Vec::new();
+    for zone in zones {
+        let (send, receive) = unbounded();
+        from_mqtt_send.push(send);
+        tasks.push(task::spawn(supervise(
+            zone,
+            settings.clone(),
+            client.clone(),
+            influx_client.clone(),
+            receive,
+            shutdown_sender.subscribe(),
+        )));
+    }
 
-    let shutdown_receiver = shutdown_sender.subscribe();
-    let mut tmanager = TManager::new(
-        thermostazv,
-        thermostazv_cmd_receive,
-        thermostazv_watch_send,
-        to_uart_send3,
-        shutdown_receiver,
-    );
-    tasks.push(task::spawn(async move { tmanager.manage().await }));
-    let shutdown_receiver = shutdown_sender.subscribe();
-    tasks.push(task::spawn(smanager(
-        status_cmd_receive,
-        status_watch_send,
-        shutdown_receiver,
-    )));
     let shutdown_receiver = shutdown_sender.subscribe();
     tasks.push(task::spawn(mqtt_connection(
         connection,
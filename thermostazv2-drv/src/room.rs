@@ -0,0 +1,83 @@
+//! A heated room, to drive the thermostat without hardware
+
+use std::time::Duration;
+
+/// Longest step of the integration
+const STEP: Duration = Duration::from_secs(30);
+
+/// First order model of a room losing heat through its walls,
+/// with a heater which takes some time to warm up and cool down
+#[derive(clap::Args, Debug, Clone, Copy, PartialEq)]
+pub struct Room {
+    /// Starting room temperature, in °C
+    #[arg(long, default_value_t = 15.0)]
+    pub inside: f64,
+
+    /// Outside temperature, in °C
+    #[arg(long, default_value_t = 5.0)]
+    pub outside: f64,
+
+    /// Time constant of the losses through the walls, in hours
+    #[arg(long, default_value_t = 10.0)]
+    pub tau: f64,
+
+    /// Warming rate of the room by the heater at full power, in °C per hour
+    #[arg(long, default_value_t = 2.0)]
+    pub power: f64,
+
+    /// Time constant of the heater, in hours
+    #[arg(long, default_value_t = 0.25)]
+    pub lag: f64,
+
+    /// Current warming rate of the heater, in °C per hour
+    #[arg(skip)]
+    pub heat: f64,
+}
+
+impl Room {
+    /// Let `dt` pass, with the heater on if `hot`
+    pub fn step(&mut self, hot: bool, dt: Duration) {
+        let mut left = dt;
+        while !left.is_zero() {
+            let step = left.min(STEP);
+            left -= step;
+            let h = step.as_secs_f64() / 3600.0;
+            let power = if hot { self.power } else { 0.0 };
+            self.heat = (power - self.heat).mul_add((h / self.lag).min(1.0), self.heat);
+            self.inside =
+                (self.heat + (self.outside - self.inside) / self.tau).mul_add(h, self.inside);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hours(h: f64) -> Duration {
+        Duration::from_secs_f64(h * 3600.0)
+    }
+
+    #[test]
+    fn heat_and_cool() {
+        let mut room = Room {
+            inside: 15.0,
+            outside: 5.0,
+            tau: 10.0,
+            power: 2.0,
+            lag: 0.25,
+            heat: 0.0,
+        };
+        room.step(true, hours(1.0));
+        assert!(room.inside > 15.5 && room.inside < 17.0, "{room:?}");
+        // the heater is still warm
+        let warm = room.inside;
+        room.step(false, hours(0.02));
+        assert!(room.inside > warm, "{room:?}");
+        room.step(false, hours(100.0));
+        assert!((room.inside - room.outside).abs() < 0.01, "{room:?}");
+        // a heater on for ever holds the room at outside + power * tau
+        room.step(true, hours(200.0));
+        assert!((room.inside - 25.0).abs() < 0.01, "{room:?}");
+    }
+}
@@ -1,35 +1,57 @@
 use crate::err::ThermostazvError;
 use bytes::BufMut;
 use bytes::BytesMut;
-use thermostazv2_lib::Cmd;
-use tokio_util::codec::{Decoder, Encoder};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use thermostazv2_lib::{
+    Cmd, Frame, Identity, FRAME_SIZE, PROTOCOL_MIN_VERSION, PROTOCOL_VERSION, USB_PID, USB_VID,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::timeout;
+use tokio_serial::{SerialPortBuilderExt, SerialPortInfo, SerialPortType};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+pub type Uart = Framed<tokio_serial::SerialStream, SerialConnection>;
+
+/// How long the firmware has to answer our `Hello`
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[derive(Debug)]
 pub struct SerialConnection {}
 
 impl Decoder for SerialConnection {
-    type Item = Cmd;
+    type Item = Frame;
     type Error = ThermostazvError;
 
     #[tracing::instrument]
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Ok(if Some(&0) == src.last() {
+        while let Some(end) = src.iter().position(|&b| b == 0) {
+            let mut frame = src.split_to(end + 1);
+            if end == 0 {
+                continue;
+            }
             tracing::trace!("decoding...");
-            Some(Cmd::from_vec(src)?)
-        } else {
-            tracing::trace!("not enough bytes yet...");
-            None
-        })
+            match Frame::from_vec(&mut frame) {
+                Ok(frame) => return Ok(Some(frame)),
+                Err(e) => tracing::warn!("dropping frame {:?}: {}", frame, e),
+            }
+        }
+        if src.len() > FRAME_SIZE {
+            tracing::warn!("dropping {} bytes without delimiter", src.len());
+            src.clear();
+        }
+        tracing::trace!("not enough bytes yet...");
+        Ok(None)
     }
 }
 
-impl Encoder<Cmd> for SerialConnection {
+impl Encoder<Frame> for SerialConnection {
     type Error = ThermostazvError;
 
     #[tracing::instrument]
-    fn encode(&mut self, cmd: Cmd, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        tracing::trace!("encode {:?}", cmd);
-        let data = cmd.to_vec()?;
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        tracing::trace!("encode {:?}", frame);
+        let data = frame.to_vec()?;
         buf.reserve(data.len());
         buf.put(data.as_slice());
         Ok(())
@@ -41,3 +63,350 @@ impl SerialConnection {
         Self {}
     }
 }
+
+/// The board among `ports`, by its USB IDs and, if given, its serial number
+fn pick<'a>(ports: &'a [SerialPortInfo], serial: Option<&str>) -> Option<&'a SerialPortInfo> {
+    let mut boards = ports.iter().filter(|port| match port.port_type {
+        SerialPortType::UsbPort(ref usb) => {
+            usb.vid == USB_VID
+                && usb.pid == USB_PID
+                && (serial.is_none() || usb.serial_number.as_deref() == serial)
+        }
+        _ => false,
+    });
+    let board = boards.next();
+    if let (Some(board), Some(other)) = (board, boards.next()) {
+        tracing::warn!(
+            "several boards found, taking {} over {}",
+            board.port_name,
+            other.port_name
+        );
+    }
+    board
+}
+
+/// Serial port of the board with USB serial number `serial`, or of any board
+pub fn discover(serial: Option<&str>) -> Result<String, ThermostazvError> {
+    // without libudev, the ports come without their USB IDs
+    if cfg!(all(target_os = "linux", not(feature = "udev"))) {
+        return Err(ThermostazvError::NotFound(
+            ": give its port, or build with the udev feature to find it".to_string(),
+        ));
+    }
+    let ports = tokio_serial::available_ports()?;
+    pick(&ports, serial)
+        .map(|port| port.port_name.clone())
+        .ok_or_else(|| {
+            ThermostazvError::NotFound(
+                serial.map_or_else(String::new, |s| format!(" with serial {s}")),
+            )
+        })
+}
+
+/// Open the serial port of the firmware
+pub fn open(port: &str, baud: u32) -> Result<Uart, ThermostazvError> {
+    let mut uart_port = tokio_serial::new(port, baud).open_native_async()?;
+    uart_port.set_exclusive(false)?;
+    Ok(SerialConnection::new().framed(uart_port))
+}
+
+/// Exchange `Hello` / `HelloAck` with the firmware, and check that we speak the same protocol.
+/// Its `Identity` tells what it `understands` of ours.
+pub async fn handshake<T>(
+    uart: &mut Framed<T, SerialConnection>,
+) -> Result<Identity, ThermostazvError>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    uart.send(Frame {
+        seq: 0,
+        cmd: Cmd::Hello(PROTOCOL_VERSION),
+    })
+    .await?;
+    let identity = timeout(HANDSHAKE_TIMEOUT, async {
+        while let Some(frame) = uart.next().await {
+            match frame?.cmd {
+                Cmd::HelloAck(identity) => return Ok(identity),
+                cmd => tracing::debug!("ignoring {:?} during handshake", cmd),
+            }
+        }
+        Err(ThermostazvError::Closed)
+    })
+    .await??;
+
+    tracing::info!(
+        "connected to firmware {} (protocol {}, capabilities {:#x})",
+        identity.firmware,
+        identity.protocol,
+        identity.capabilities.0
+    );
+    if !(PROTOCOL_MIN_VERSION..=PROTOCOL_VERSION).contains(&identity.protocol) {
+        return Err(ThermostazvError::Protocol(identity.protocol));
+    }
+    if identity.protocol < PROTOCOL_VERSION {
+        tracing::warn!(
+            "firmware speaks protocol {}, downgrading from {}",
+            identity.protocol,
+            PROTOCOL_VERSION
+        );
+    }
+    Ok(identity)
+}
+
+/// Whether the firmware we shook hands with knows `cmd` and has what it takes to carry it out
+pub const fn understands(identity: &Identity, cmd: &Cmd) -> bool {
+    identity.protocol >= cmd.since() && identity.capabilities.contains(cmd.needs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use thermostazv2_lib::{
+        Capabilities, Failsafe, FailsafeAction, FirmwareVersion, Identity, Mode, NackReason, Relay,
+        SensorErr, SensorOk, SensorResult, Setpoint,
+    };
+    use tokio::io::DuplexStream;
+    use tokio_serial::UsbPortInfo;
+
+    type Link = Framed<DuplexStream, SerialConnection>;
+
+    /// Both ends of a serial link, the host one and the firmware one
+    fn link() -> (Link, Link) {
+        let (host, board) = tokio::io::duplex(4 * FRAME_SIZE);
+        (
+            SerialConnection::new().framed(host),
+            SerialConnection::new().framed(board),
+        )
+    }
+
+    fn identity(protocol: u16, capabilities: Capabilities) -> Identity {
+        Identity {
+            protocol,
+            firmware: FirmwareVersion::from("test"),
+            capabilities,
+        }
+    }
+
+    /// Wait for the `Hello` of the host, and answer `cmds`
+    async fn answer(board: &mut Link, cmds: Vec<Cmd>) -> Result<(), ThermostazvError> {
+        let hello = board.next().await.ok_or(ThermostazvError::Closed)??;
+        assert_eq!(hello.cmd, Cmd::Hello(PROTOCOL_VERSION));
+        for (seq, cmd) in (1..).zip(cmds) {
+            board.send(Frame { seq, cmd }).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handshake_older() {
+        let (mut host, mut board) = link();
+        let old = identity(PROTOCOL_MIN_VERSION, Capabilities::RELAY);
+        let cmds = vec![Cmd::Pong, Cmd::HelloAck(old.clone())];
+        let (res, answered) = tokio::join!(handshake(&mut host), answer(&mut board, cmds));
+        assert!(answered.is_ok());
+        let Ok(identity) = res else {
+            panic!("handshake failed: {res:?}");
+        };
+        assert_eq!(identity, old);
+        assert!(understands(&identity, &Cmd::Set(Relay::Hot)));
+        assert!(!understands(&identity, &Cmd::SetMode(Mode::Local)));
+    }
+
+    #[tokio::test]
+    async fn handshake_newer() {
+        let (mut host, mut board) = link();
+        let new = identity(PROTOCOL_VERSION + 1, Capabilities::RELAY);
+        let cmds = vec![Cmd::HelloAck(new)];
+        let (res, _) = tokio::join!(handshake(&mut host), answer(&mut board, cmds));
+        assert!(
+            matches!(res, Err(ThermostazvError::Protocol(p)) if p == PROTOCOL_VERSION + 1),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_timeout() {
+        let (mut host, mut board) = link();
+        let (res, _) = tokio::join!(handshake(&mut host), answer(&mut board, vec![Cmd::Pong]));
+        assert!(matches!(res, Err(ThermostazvError::Timeout(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn handshake_closed() {
+        let (mut host, mut board) = link();
+        let firmware = async move {
+            answer(&mut board, vec![Cmd::Pong]).await?;
+            drop(board);
+            Ok::<_, ThermostazvError>(())
+        };
+        let (res, _) = tokio::join!(handshake(&mut host), firmware);
+        assert!(matches!(res, Err(ThermostazvError::Closed)), "{res:?}");
+    }
+
+    #[test]
+    fn pick_board() {
+        let usb = |name: &str, vid, serial: &str| SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid: USB_PID,
+                serial_number: Some(serial.to_string()),
+                manufacturer: None,
+                product: None,
+            }),
+        };
+        let ports = [
+            SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::Unknown,
+            },
+            usb("/dev/ttyACM0", 0x1234, "A"),
+            usb("/dev/ttyACM1", USB_VID, "B"),
+            usb("/dev/ttyACM2", USB_VID, "C"),
+        ];
+        let name = |serial| pick(&ports, serial).map(|p| p.port_name.as_str());
+        assert_eq!(name(None), Some("/dev/ttyACM1"));
+        assert_eq!(name(Some("C")), Some("/dev/ttyACM2"));
+        assert_eq!(name(Some("A")), None);
+    }
+
+    fn relay() -> impl Strategy<Value = Relay> {
+        prop_oneof![Just(Relay::Hot), Just(Relay::Cold)]
+    }
+
+    fn sensor() -> impl Strategy<Value = SensorResult> {
+        prop_oneof![
+            any::<(u32, u32)>().prop_map(|(h, t)| SensorResult::Ok(SensorOk { h, t })),
+            prop_oneof![
+                Just(SensorErr::Uncalibrated),
+                Just(SensorErr::Bus),
+                Just(SensorErr::CheckSum),
+                Just(SensorErr::Uninitialized),
+            ]
+            .prop_map(SensorResult::Err),
+        ]
+    }
+
+    fn mode() -> impl Strategy<Value = Mode> {
+        prop_oneof![Just(Mode::Host), Just(Mode::Failsafe), Just(Mode::Local)]
+    }
+
+    fn setpoint() -> impl Strategy<Value = Setpoint> {
+        any::<(i16, u16)>().prop_map(|(target, band)| Setpoint { target, band })
+    }
+
+    fn cmd() -> impl Strategy<Value = Cmd> {
+        prop_oneof![
+            Just(Cmd::Get),
+            Just(Cmd::Ping),
+            Just(Cmd::Pong),
+            relay().prop_map(Cmd::Set),
+            (relay(), sensor(), mode()).prop_map(|(r, s, m)| Cmd::Status(r, s, m)),
+            any::<u16>().prop_map(Cmd::Hello),
+            (any::<u16>(), "[0-9a-z.-]{0,16}", any::<u32>()).prop_map(|(p, f, c)| {
+                Cmd::HelloAck(Identity {
+                    protocol: p,
+                    firmware: FirmwareVersion::from(f.as_str()),
+                    capabilities: Capabilities(c),
+                })
+            }),
+            any::<u16>().prop_map(Cmd::Ack),
+            (
+                any::<u16>(),
+                prop_oneof![
+                    Just(NackReason::Unexpected),
+                    Just(NackReason::Busy),
+                    Just(NackReason::Mode),
+                    Just(NackReason::Corrupted),
+                ]
+            )
+                .prop_map(|(seq, reason)| Cmd::Nack(seq, reason)),
+            (
+                any::<u16>(),
+                prop_oneof![
+                    Just(FailsafeAction::Cold),
+                    setpoint().prop_map(FailsafeAction::Regulate),
+                ]
+            )
+                .prop_map(|(timeout, action)| Cmd::SetFailsafe(Failsafe { timeout, action })),
+            setpoint().prop_map(Cmd::SetLocal),
+            mode().prop_map(Cmd::SetMode),
+        ]
+    }
+
+    fn frame() -> impl Strategy<Value = Frame> {
+        (any::<u16>(), cmd()).prop_map(|(seq, cmd)| Frame { seq, cmd })
+    }
+
+    fn stream(frames: &[Frame]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for frame in frames {
+            let mut buf = BytesMut::new();
+            assert!(SerialConnection::new()
+                .encode(frame.clone(), &mut buf)
+                .is_ok());
+            bytes.extend_from_slice(&buf);
+        }
+        bytes
+    }
+
+    /// Feed `bytes` to a decoder, cut at `cuts`, and collect every decoded `Frame`
+    fn feed(bytes: &[u8], cuts: &[prop::sample::Index]) -> (Vec<Frame>, BytesMut) {
+        let mut cuts: Vec<usize> = cuts.iter().map(|i| i.index(bytes.len() + 1)).collect();
+        cuts.push(bytes.len());
+        cuts.sort_unstable();
+
+        let mut decoder = SerialConnection::new();
+        let mut buf = BytesMut::new();
+        let mut out = Vec::new();
+        let mut start = 0;
+        for cut in cuts {
+            buf.extend_from_slice(&bytes[start..cut]);
+            start = cut;
+            while let Ok(Some(frame)) = decoder.decode(&mut buf) {
+                out.push(frame);
+            }
+        }
+        (out, buf)
+    }
+
+    proptest! {
+        #[test]
+        fn decode_chunked(
+            frames in prop::collection::vec(frame(), 0..16),
+            cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..16),
+        ) {
+            let (out, rest) = feed(&stream(&frames), &cuts);
+            prop_assert_eq!(out, frames);
+            prop_assert!(rest.is_empty());
+        }
+
+        #[test]
+        fn decode_after_garbage(
+            garbage in prop::collection::vec(any::<u8>(), 0..64),
+            frames in prop::collection::vec(frame(), 1..16),
+            cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..16),
+        ) {
+            let mut bytes = garbage;
+            bytes.push(0);
+            bytes.extend(stream(&frames));
+            let (out, rest) = feed(&bytes, &cuts);
+            prop_assert!(out.ends_with(&frames), "{:?} does not end with {:?}", out, frames);
+            prop_assert!(rest.is_empty());
+        }
+
+        #[test]
+        fn decode_after_overflow(
+            frames in prop::collection::vec(frame(), 1..16),
+            cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..16),
+        ) {
+            let mut bytes = vec![1; 2 * FRAME_SIZE];
+            bytes.push(0);
+            bytes.extend(stream(&frames));
+            let (out, rest) = feed(&bytes, &cuts);
+            prop_assert!(out.ends_with(&frames), "{:?} does not end with {:?}", out, frames);
+            prop_assert!(rest.is_empty());
+        }
+    }
+}
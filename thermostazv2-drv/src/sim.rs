@@ -0,0 +1,377 @@
+//! A board on a pseudo-terminal, speaking the protocol of the firmware and heating a `Room`
+
+use crate::err::{ThermostazvError, ThermostazvResult};
+use crate::room::Room;
+use crate::sercon::SerialConnection;
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use thermostazv2_lib::{
+    Capabilities, Cmd, Failsafe, FailsafeAction, FirmwareVersion, Frame, Identity, Mode,
+    NackReason, Relay, SensorErr, SensorOk, SensorResult, Seq, Setpoint, MIN_HOLD,
+    PROTOCOL_VERSION,
+};
+use tokio_serial::SerialPort;
+use tokio_util::codec::Decoder;
+
+/// Seconds between two readings of the AHT20, each one followed by a `Cmd::Status`
+const READ_PERIOD: u32 = 5;
+
+/// How the AHT20 fails, in turn
+const SENSOR_ERRORS: [SensorErr; 3] =
+    [SensorErr::Bus, SensorErr::CheckSum, SensorErr::Uncalibrated];
+
+#[derive(clap::Args, Debug)]
+pub struct Simulate {
+    /// Simulated seconds per real second
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+
+    /// One AHT20 reading out of that many fails, 0 for none
+    #[arg(long, default_value_t = 0)]
+    sensor_errors: u32,
+
+    #[command(flatten)]
+    room: Room,
+}
+
+/// Raw AHT20 reading of `celsius`, at 50% relative humidity
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn raw(celsius: f64) -> SensorOk {
+    SensorOk {
+        h: 1 << 19,
+        t: ((celsius + 50.0) / 200.0 * f64::from(1 << 20)).clamp(0.0, f64::from(1 << 20)) as u32,
+    }
+}
+
+/// What the firmware keeps track of
+#[derive(Debug)]
+struct Board {
+    room: Room,
+    /// simulated time of a tick
+    step: Duration,
+    sensor_errors: u32,
+    relay: Relay,
+    mode: Mode,
+    failsafe: Failsafe,
+    local: Setpoint,
+    sensor: SensorResult,
+    /// seconds since the last frame of the host
+    silence: u16,
+    /// seconds since the last switch of the relay
+    held: u16,
+    /// seconds since the last reading
+    ticks: u32,
+    reads: u32,
+}
+
+impl Board {
+    fn new(args: &Simulate) -> Result<Self, ThermostazvError> {
+        let step = Duration::try_from_secs_f64(args.speed.max(0.0))
+            .map_err(|e| ThermostazvError::Argument(format!("speed {}: {e}", args.speed)))?;
+        Ok(Self {
+            room: args.room,
+            step,
+            sensor_errors: args.sensor_errors,
+            relay: Relay::Cold,
+            mode: Mode::Host,
+            failsafe: Failsafe::default(),
+            local: Setpoint::from_celsius(17.0, 0.5),
+            sensor: SensorResult::Err(SensorErr::Uninitialized),
+            silence: 0,
+            held: 0,
+            ticks: 0,
+            reads: 0,
+        })
+    }
+
+    fn identity() -> Identity {
+        let mut firmware = FirmwareVersion::new();
+        firmware.push_str("sim-").ok();
+        firmware.push_str(env!("CARGO_PKG_VERSION")).ok();
+        Identity {
+            protocol: PROTOCOL_VERSION,
+            firmware,
+            capabilities: Capabilities::RELAY
+                | Capabilities::AHT20
+                | Capabilities::FAILSAFE
+                | Capabilities::LOCAL,
+        }
+    }
+
+    const fn status(&self) -> Cmd {
+        Cmd::Status(self.relay, self.sensor, self.mode)
+    }
+
+    /// Drive the relay by ourselves while in failsafe or local mode, holding it `MIN_HOLD`
+    /// seconds at least, unless there is nothing to regulate on
+    fn regulate(&mut self) {
+        let setpoint = match (self.mode, self.failsafe.action) {
+            (Mode::Host, _) => return,
+            (Mode::Local, _) => Some(self.local),
+            (Mode::Failsafe, FailsafeAction::Regulate(setpoint)) => Some(setpoint),
+            (Mode::Failsafe, FailsafeAction::Cold) => None,
+        };
+        let (hot, forced) = match (setpoint, self.sensor) {
+            (Some(setpoint), SensorResult::Ok(s)) => (
+                setpoint.regulate(self.relay == Relay::Hot, s.celsius()),
+                false,
+            ),
+            _ => (false, true),
+        };
+        if Relay::from(hot) == self.relay || (self.held < MIN_HOLD && !forced) {
+            return;
+        }
+        self.held = 0;
+        self.relay = Relay::from(hot);
+    }
+
+    /// Answers to a frame of the host, then its `Ack` or `Nack`
+    fn receive(&mut self, frame: Frame) -> Vec<Cmd> {
+        let Frame { seq, cmd } = frame;
+        self.silence = 0;
+        let mut replies = Vec::new();
+        let res = match cmd {
+            Cmd::Get => {
+                replies.push(self.status());
+                Ok(())
+            }
+            Cmd::Set(_) if self.mode == Mode::Local => Err(NackReason::Mode),
+            Cmd::Set(relay) => {
+                if self.mode == Mode::Failsafe {
+                    tracing::info!("host is back, leaving failsafe");
+                    self.mode = Mode::Host;
+                }
+                if self.relay != relay {
+                    self.held = 0;
+                }
+                self.relay = relay;
+                Ok(())
+            }
+            Cmd::Ping => {
+                replies.push(Cmd::Pong);
+                Ok(())
+            }
+            Cmd::Hello(version) => {
+                tracing::info!("host speaks protocol {}", version);
+                replies.push(Cmd::HelloAck(Self::identity()));
+                Ok(())
+            }
+            Cmd::Status(..) | Cmd::HelloAck(_) | Cmd::Ack(_) | Cmd::Nack(..) => {
+                tracing::warn!("wrong cmd received: {:?}", cmd);
+                Err(NackReason::Unexpected)
+            }
+            Cmd::Pong => Ok(()),
+            Cmd::SetFailsafe(failsafe) => {
+                self.failsafe = failsafe;
+                Ok(())
+            }
+            Cmd::SetLocal(setpoint) => {
+                self.local = setpoint;
+                self.regulate();
+                Ok(())
+            }
+            Cmd::SetMode(Mode::Failsafe) => Err(NackReason::Mode),
+            Cmd::SetMode(mode) => {
+                tracing::info!("mode {:?} -> {:?}", self.mode, mode);
+                self.mode = mode;
+                self.regulate();
+                Ok(())
+            }
+        };
+        replies.push(res.map_or_else(|r| Cmd::Nack(seq, r), |()| Cmd::Ack(seq)));
+        replies
+    }
+
+    /// Read the AHT20, which fails once every `sensor_errors` readings
+    fn read(&mut self) {
+        self.reads += 1;
+        self.sensor = if self.reads.checked_rem(self.sensor_errors) == Some(0) {
+            let turn = (self.reads / self.sensor_errors) as usize % SENSOR_ERRORS.len();
+            SensorResult::Err(SENSOR_ERRORS[turn])
+        } else {
+            SensorResult::Ok(raw(self.room.inside))
+        };
+    }
+
+    /// A second goes by, what we send then
+    fn tick(&mut self) -> Option<Cmd> {
+        self.room.step(self.relay == Relay::Hot, self.step);
+        self.silence = self.silence.saturating_add(1);
+        self.held = self.held.saturating_add(1);
+        if self.failsafe.timeout != 0
+            && self.silence >= self.failsafe.timeout
+            && self.mode == Mode::Host
+        {
+            tracing::info!("host silent for {}s, entering failsafe", self.silence);
+            self.mode = Mode::Failsafe;
+            self.regulate();
+        }
+        self.ticks += 1;
+        if self.ticks < READ_PERIOD {
+            return None;
+        }
+        self.ticks = 0;
+        self.read();
+        self.regulate();
+        Some(self.status())
+    }
+}
+
+/// Pretend to be a board on a new pseudo-terminal until interrupted
+pub async fn simulate(args: Simulate) -> ThermostazvResult {
+    let (master, mut slave) = tokio_serial::SerialStream::pair()?;
+    slave.set_exclusive(false)?;
+    let port = slave.name().ok_or(ThermostazvError::Closed)?;
+    tracing::info!(
+        "simulated board on {}, run the daemon with --uart-port {}",
+        port,
+        port
+    );
+    let mut uart = SerialConnection::new().framed(master);
+    let mut board = Board::new(&args)?;
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut seq: Seq = 0;
+    loop {
+        let cmds = tokio::select! {
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+            frame = uart.next() => match frame {
+                Some(Ok(frame)) => board.receive(frame),
+                Some(Err(e)) => {
+                    tracing::warn!("invalid frame: {}", e);
+                    continue;
+                }
+                None => return Err(ThermostazvError::Closed.into()),
+            },
+            _ = tick.tick() => {
+                let cmds = board.tick().into_iter().collect();
+                tracing::debug!("room {:.2}°C, relay {:?}", board.room.inside, board.relay);
+                cmds
+            },
+        };
+        for cmd in cmds {
+            seq = seq.wrapping_add(1);
+            uart.send(Frame { seq, cmd }).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn args(speed: f64) -> Simulate {
+        Simulate {
+            speed,
+            sensor_errors: 3,
+            room: Room {
+                inside: 15.0,
+                outside: 5.0,
+                tau: 10.0,
+                power: 2.0,
+                lag: 0.25,
+                heat: 0.0,
+            },
+        }
+    }
+
+    fn board() -> Board {
+        match Board::new(&args(600.0)) {
+            Ok(board) => board,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn speed() {
+        assert!(matches!(
+            Board::new(&args(f64::INFINITY)),
+            Err(ThermostazvError::Argument(_))
+        ));
+    }
+
+    #[test]
+    fn answer() {
+        let mut board = board();
+        let mut send = |cmd| {
+            let frame = Frame { seq: 7, cmd };
+            board.receive(frame)
+        };
+        assert!(matches!(
+            send(Cmd::Hello(PROTOCOL_VERSION))[..],
+            [Cmd::HelloAck(_), Cmd::Ack(7)]
+        ));
+        assert_eq!(send(Cmd::Ping), [Cmd::Pong, Cmd::Ack(7)]);
+        assert_eq!(send(Cmd::Set(Relay::Hot)), [Cmd::Ack(7)]);
+        assert_eq!(send(Cmd::SetMode(Mode::Local)), [Cmd::Ack(7)]);
+        assert_eq!(send(Cmd::Set(Relay::Hot)), [Cmd::Nack(7, NackReason::Mode)]);
+        assert_eq!(send(Cmd::Pong), [Cmd::Ack(7)]);
+        assert_eq!(send(Cmd::Ack(3)), [Cmd::Nack(7, NackReason::Unexpected)]);
+    }
+
+    #[test]
+    fn heat_and_fail() {
+        let mut board = board();
+        board.receive(Frame {
+            seq: 1,
+            cmd: Cmd::Set(Relay::Hot),
+        });
+        let statuses: Vec<Cmd> = (0..15).filter_map(|_| board.tick()).collect();
+        assert!(board.room.inside > 15.0, "{board:?}");
+        assert!(matches!(
+            statuses[..],
+            [
+                Cmd::Status(Relay::Hot, SensorResult::Ok(_), Mode::Host),
+                Cmd::Status(Relay::Hot, SensorResult::Ok(_), Mode::Host),
+                Cmd::Status(
+                    Relay::Hot,
+                    SensorResult::Err(SensorErr::CheckSum),
+                    Mode::Host
+                ),
+            ]
+        ));
+        // the host went silent, the firmware keeps the room warm
+        board.receive(Frame {
+            seq: 2,
+            cmd: Cmd::SetFailsafe(Failsafe {
+                timeout: 20,
+                action: FailsafeAction::Regulate(Setpoint::from_celsius(12.0, 0.5)),
+            }),
+        });
+        for _ in 0..20 {
+            board.tick();
+        }
+        assert_eq!(board.mode, Mode::Failsafe);
+        assert_eq!(board.relay, Relay::Cold);
+    }
+
+    #[test]
+    fn hold() {
+        let mut board = board();
+        board.sensor_errors = 0;
+        for _ in 0..READ_PERIOD {
+            board.tick();
+        }
+        for (seq, cmd) in [
+            Cmd::Set(Relay::Hot),
+            Cmd::SetLocal(Setpoint::from_celsius(10.0, 0.5)),
+            Cmd::SetMode(Mode::Local),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            board.receive(Frame {
+                seq: Seq::try_from(seq).unwrap_or_default(),
+                cmd,
+            });
+        }
+        // warm enough, but the relay was just switched on
+        for _ in 1..MIN_HOLD {
+            board.tick();
+        }
+        assert_eq!(board.relay, Relay::Hot);
+        for _ in 0..READ_PERIOD {
+            board.tick();
+        }
+        assert_eq!(board.relay, Relay::Cold);
+    }
+}
@@ -0,0 +1,331 @@
+use crate::calibration::{one, AHT20, SI7021};
+use chrono::{DateTime, Local};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// How a source gets its readings
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "lowercase", tag = "kind")]
+pub enum Input {
+    /// the AHT20 of the board, through `Cmd::Status`
+    Aht20,
+    /// a JSON payload on `topic`, the temperature at `pointer` (RFC 6901)
+    Json { topic: String, pointer: String },
+    /// a payload on `topic` which is just the temperature
+    Plain { topic: String },
+}
+
+/// A temperature sensor the thermostat can regulate on
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Source {
+    /// also the key of its calibration
+    pub name: String,
+    #[serde(flatten)]
+    pub input: Input,
+    /// with `Method::Priority`, the fresh source with the lowest priority drives the relay
+    #[serde(default)]
+    pub priority: u32,
+    /// share in `Method::Mean`, not negative
+    #[serde(default = "one")]
+    pub weight: f64,
+}
+
+impl Source {
+    /// MQTT topic to subscribe to, if any
+    pub fn topic(&self) -> Option<&str> {
+        match self.input {
+            Input::Aht20 => None,
+            Input::Json { ref topic, .. } | Input::Plain { ref topic } => Some(topic),
+        }
+    }
+
+    /// Temperature in a message received on `topic`, if it is ours
+    pub fn parse(&self, topic: &str, payload: &[u8]) -> Option<f64> {
+        if self.topic() != Some(topic) {
+            return None;
+        }
+        match self.input {
+            Input::Aht20 => None,
+            Input::Json { ref pointer, .. } => serde_json::from_slice::<Value>(payload)
+                .ok()?
+                .pointer(pointer)?
+                .as_f64(),
+            Input::Plain { .. } => std::str::from_utf8(payload).ok()?.trim().parse().ok(),
+        }
+    }
+}
+
+pub type Sources = Vec<Source>;
+
+/// `Sources` whose weights make a mean: none negative, not all zero
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Sources, D::Error> {
+    let sources = Sources::deserialize(deserializer)?;
+    if let Some(source) = sources
+        .iter()
+        .find(|s| !(0.0..=f64::MAX).contains(&s.weight))
+    {
+        return Err(de::Error::custom(format!(
+            "weight {} of {} is not 0 or more",
+            source.weight, source.name
+        )));
+    }
+    if !sources.is_empty() && sources.iter().all(|s| s.weight == 0.0) {
+        return Err(de::Error::custom("all the weights are 0"));
+    }
+    Ok(sources)
+}
+
+/// The Tasmota SI7021 first, then the AHT20 of the board
+pub fn defaults() -> Sources {
+    vec![
+        Source {
+            name: SI7021.to_string(),
+            input: Input::Json {
+                topic: "tele/tasmota_43D8FD/SENSOR".to_string(),
+                pointer: "/SI7021/Temperature".to_string(),
+            },
+            priority: 0,
+            weight: 1.0,
+        },
+        Source {
+            name: AHT20.to_string(),
+            input: Input::Aht20,
+            priority: 1,
+            weight: 1.0,
+        },
+    ]
+}
+
+/// How to get the control temperature out of the fresh readings
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    /// the source with the lowest priority
+    #[default]
+    Priority,
+    /// mean weighted by `Source::weight`, plain if every fresh reading weighs 0
+    Mean,
+    Median,
+    /// the coldest reading
+    Min,
+    /// the warmest reading
+    Max,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, Default)]
+pub struct Fusion {
+    #[serde(default)]
+    pub method: Method,
+    /// with at least three readings, drop those further than that from their median,
+    /// in °C, 0 to keep them all, as when most of them would go
+    #[serde(default)]
+    pub outlier: f64,
+}
+
+/// A fresh reading, and what the fusion did with it
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Reading {
+    pub source: String,
+    /// calibrated temperature, in °C
+    pub celsius: f64,
+    pub at: DateTime<Local>,
+    pub priority: u32,
+    pub weight: f64,
+    /// too far from the others
+    pub outlier: bool,
+    /// part of the control temperature
+    pub used: bool,
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    values.sort_unstable_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        n if n % 2 == 0 => Some(values[mid - 1].mul_add(0.5, values[mid] * 0.5)),
+        _ => Some(values[mid]),
+    }
+}
+
+impl Fusion {
+    /// Control temperature out of `readings`, flagging the outliers and the ones used
+    pub fn fuse(&self, readings: &mut [Reading]) -> Option<f64> {
+        if self.outlier > 0.0 && readings.len() >= 3 {
+            let center = median(readings.iter().map(|r| r.celsius).collect())?;
+            let outlier = |r: &Reading| (r.celsius - center).abs() > self.outlier;
+            let agree = readings.iter().filter(|r| !outlier(r)).count();
+            // only when most readings agree, otherwise we cannot tell which ones are wrong
+            if agree * 2 > readings.len() {
+                for reading in readings.iter_mut() {
+                    reading.outlier = outlier(reading);
+                }
+            }
+        }
+        let mut kept: Vec<&mut Reading> = readings.iter_mut().filter(|r| !r.outlier).collect();
+        let one = match self.method {
+            Method::Priority => kept.iter_mut().min_by_key(|r| r.priority),
+            Method::Min => kept
+                .iter_mut()
+                .min_by(|a, b| a.celsius.total_cmp(&b.celsius)),
+            Method::Max => kept
+                .iter_mut()
+                .max_by(|a, b| a.celsius.total_cmp(&b.celsius)),
+            Method::Mean | Method::Median => {
+                // when only sources of weight 0 are fresh, their plain mean beats no temperature
+                let weighted = self.method == Method::Mean && kept.iter().any(|r| r.weight > 0.0);
+                let weight = |r: &Reading| if weighted { r.weight } else { 1.0 };
+                let weights: f64 = kept.iter().map(|r| weight(r)).sum();
+                let value = if self.method == Method::Median {
+                    median(kept.iter().map(|r| r.celsius).collect())
+                } else if weights > 0.0 {
+                    Some(kept.iter().map(|r| r.celsius * weight(r)).sum::<f64>() / weights)
+                } else {
+                    None
+                };
+                if value.is_some() {
+                    for reading in &mut kept {
+                        reading.used = weight(reading) > 0.0;
+                    }
+                }
+                return value;
+            }
+        };
+        one.map(|reading| {
+            reading.used = true;
+            reading.celsius
+        })
+    }
+}
+
+/// Names of the readings in use, as `Thermostazv::source`
+pub fn used(readings: &[Reading]) -> Option<String> {
+    let names: Vec<&str> = readings
+        .iter()
+        .filter(|r| r.used)
+        .map(|r| r.source.as_str())
+        .collect();
+    (!names.is_empty()).then(|| names.join("+"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let sources = defaults();
+        let payload =
+            br#"{"Time":"2023-03-10T12:00:00","SI7021":{"Temperature":19.4,"Humidity":48.2}}"#;
+        assert_eq!(
+            sources[0].parse("tele/tasmota_43D8FD/SENSOR", payload),
+            Some(19.4)
+        );
+        assert_eq!(sources[0].parse("tele/other/SENSOR", payload), None);
+        assert_eq!(sources[0].parse("tele/tasmota_43D8FD/SENSOR", b"{}"), None);
+        assert_eq!(
+            sources[1].parse("tele/tasmota_43D8FD/SENSOR", payload),
+            None
+        );
+        let plain = Source {
+            name: "salon".to_string(),
+            input: Input::Plain {
+                topic: "salon/temperature".to_string(),
+            },
+            priority: 2,
+            weight: 1.0,
+        };
+        assert_eq!(plain.parse("salon/temperature", b" 20.5\n"), Some(20.5));
+        assert_eq!(plain.parse("salon/temperature", b"hot"), None);
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Config {
+        #[serde(deserialize_with = "deserialize")]
+        sources: Sources,
+    }
+
+    #[test]
+    fn config() {
+        let toml = "name = \"salon\"\nkind = \"json\"\ntopic = \"zigbee2mqtt/salon\"\npointer = \"/temperature\"\n";
+        let source: Result<Source, _> = toml::from_str(toml);
+        assert!(source.is_ok(), "{source:?}");
+        let source = source.ok();
+        assert_eq!(
+            source.as_ref().and_then(Source::topic),
+            Some("zigbee2mqtt/salon")
+        );
+        assert_eq!(source.map(|s| (s.priority, s.weight)), Some((0, 1.0)));
+
+        let weights = |a: f64, b: f64| {
+            let source = |name, weight| {
+                format!("[[sources]]\nname = \"{name}\"\nkind = \"aht20\"\nweight = {weight:?}\n")
+            };
+            toml::from_str::<Config>(&(source("a", a) + &source("b", b))).map(|c| c.sources.len())
+        };
+        assert_eq!(weights(1.0, 0.0).ok(), Some(2));
+        assert!(weights(-1.0, 1.0).is_err());
+        assert!(weights(0.0, 0.0).is_err());
+    }
+
+    fn readings(values: &[(f64, u32, f64)]) -> Vec<Reading> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &(celsius, priority, weight))| Reading {
+                source: format!("s{i}"),
+                celsius,
+                at: Local::now(),
+                priority,
+                weight,
+                outlier: false,
+                used: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fuse() {
+        let fuse = |method, outlier, values: &[(f64, u32, f64)]| {
+            let mut readings = readings(values);
+            let value = Fusion { method, outlier }.fuse(&mut readings);
+            (value, used(&readings))
+        };
+        let values = [(19.0, 1, 1.0), (20.0, 0, 3.0), (26.0, 2, 0.0)];
+        assert_eq!(
+            fuse(Method::Priority, 0.0, &values),
+            (Some(20.0), Some("s1".to_string()))
+        );
+        assert_eq!(fuse(Method::Min, 0.0, &values).0, Some(19.0));
+        assert_eq!(fuse(Method::Max, 0.0, &values).0, Some(26.0));
+        assert_eq!(fuse(Method::Median, 0.0, &values).0, Some(20.0));
+        assert_eq!(
+            fuse(Method::Mean, 0.0, &values),
+            (Some(19.75), Some("s0+s1".to_string()))
+        );
+        // 26°C is too far from the median
+        assert_eq!(
+            fuse(Method::Max, 2.0, &values),
+            (Some(20.0), Some("s1".to_string()))
+        );
+        assert_eq!(fuse(Method::Median, 2.0, &values).0, Some(19.5));
+        // not enough readings to tell which one is wrong
+        assert_eq!(fuse(Method::Max, 2.0, &values[1..]).0, Some(26.0));
+        // nor two against two
+        let split = [
+            (10.0, 0, 1.0),
+            (10.0, 0, 1.0),
+            (30.0, 0, 1.0),
+            (30.0, 0, 1.0),
+        ];
+        assert_eq!(
+            fuse(Method::Mean, 2.0, &split),
+            (Some(20.0), Some("s0+s1+s2+s3".to_string()))
+        );
+        assert_eq!(fuse(Method::Mean, 0.0, &[]), (None, None));
+        // the sources which count are all stale
+        assert_eq!(
+            fuse(Method::Mean, 0.0, &[(19.0, 0, 0.0), (20.0, 0, 0.0)]),
+            (Some(19.5), Some("s0+s1".to_string()))
+        );
+    }
+}
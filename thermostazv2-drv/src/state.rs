@@ -0,0 +1,192 @@
+use crate::sources::{self, Fusion, Reading};
+use crate::thermostazv::{week_time, Frost, Manual, StrategyDef, Thermostazv};
+use chrono::{DateTime, Local};
+use heapless::Vec;
+use serde::Serialize;
+use thermostazv2_lib::control::{Cycling, Slot, Strategy, SLOTS};
+use thermostazv2_lib::{Cmd, Mode, Relay, SensorErr, SensorOk, SensorResult};
+
+/// Setpoints and hours of the day / night schedule, or the weekly slots
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Schedule {
+    pub day: f64,
+    pub night: f64,
+    pub empty: f64,
+    pub morning: u32,
+    pub evening: u32,
+    pub slots: Vec<Slot, SLOTS>,
+    /// index of the slot in use
+    pub slot: Option<usize>,
+}
+
+/// Everything worth knowing about the thermostat, as published on MQTT
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Serialize, Debug, PartialEq)]
+pub struct State {
+    /// relay reported by the firmware
+    pub relay: bool,
+    /// relay we asked for
+    pub hot: bool,
+    pub mode: Option<Mode>,
+    pub present: bool,
+    pub local: bool,
+    /// control temperature, fused from `readings`
+    pub current: Option<f64>,
+    /// when the newest reading in `current` was taken, RFC 3339
+    pub current_at: Option<String>,
+    /// no fresh reading, the frost protection has the relay
+    pub stale: bool,
+    /// sources driving the relay
+    pub source: Option<String>,
+    pub fusion: Fusion,
+    /// fresh readings, and what the fusion made of them
+    pub readings: std::vec::Vec<Reading>,
+    pub target: f64,
+    /// temperature at which the relay will switch
+    pub threshold: f64,
+    pub schedule: Schedule,
+    /// away mode: hold `empty` until then, RFC 3339
+    pub away_until: Option<String>,
+    /// when away mode ends to heat for our return, RFC 3339
+    pub return_at: Option<String>,
+    /// measured heating rate, in °C per hour
+    pub heating_rate: f64,
+    /// manual override of the schedule
+    pub manual: Option<Manual>,
+    /// seconds before the manual override ends
+    pub manual_remaining: Option<i64>,
+    pub cycling: Cycling,
+    /// relay switches refused by the short cycle protection
+    pub suppressed: u32,
+    #[serde(with = "StrategyDef")]
+    pub strategy: Strategy,
+    pub frost: Frost,
+    /// share of the cycle the relay is on, with PID
+    pub duty: Option<f64>,
+    /// AHT20 temperature, in °C
+    pub temperature: Option<f64>,
+    /// AHT20 relative humidity, in %
+    pub humidity: Option<f64>,
+    /// AHT20 raw reading
+    pub raw: Option<SensorOk>,
+    pub sensor_error: Option<SensorErr>,
+    /// when this state was built, RFC 3339
+    pub timestamp: String,
+    /// when the firmware status last changed, RFC 3339
+    pub status_timestamp: Option<String>,
+}
+
+impl State {
+    pub fn new(
+        thermostazv: &Thermostazv,
+        status: &Cmd,
+        status_at: Option<DateTime<Local>>,
+    ) -> Self {
+        let (relay, sensor, mode) = match status {
+            Cmd::Status(relay, sensor, mode) => (*relay == Relay::Hot, Some(sensor), Some(*mode)),
+            _ => (thermostazv.thermostat.hot, None, None),
+        };
+        let (raw, sensor_error) = match sensor {
+            Some(SensorResult::Ok(sensor)) => (Some(*sensor), None),
+            Some(SensorResult::Err(e)) => (None, Some(*e)),
+            None => (None, None),
+        };
+        let calibrated = raw.map(|s| thermostazv.calibrated_aht20(s));
+        let thermostat = &thermostazv.thermostat;
+        let (current, readings) = thermostazv.current(Local::now());
+        Self {
+            relay,
+            hot: thermostat.hot,
+            mode,
+            present: thermostat.present,
+            local: thermostazv.local,
+            current,
+            current_at: readings
+                .iter()
+                .filter(|r| r.used)
+                .map(|r| r.at)
+                .max()
+                .map(|t| t.to_rfc3339()),
+            stale: thermostazv.stale(Local::now()),
+            source: sources::used(&readings),
+            fusion: thermostazv.fusion,
+            readings,
+            target: thermostazv.target(Local::now()),
+            threshold: thermostazv.hysteresis(Local::now()),
+            schedule: Schedule {
+                day: thermostat.day,
+                night: thermostat.night,
+                empty: thermostat.empty,
+                morning: thermostat.morning,
+                evening: thermostat.evening,
+                slots: thermostat.schedule.clone(),
+                slot: thermostat.slot(week_time(Local::now())),
+            },
+            away_until: thermostazv.away_until.map(|t| t.to_rfc3339()),
+            return_at: thermostazv.return_at(Local::now()).map(|t| t.to_rfc3339()),
+            heating_rate: thermostazv.heating_rate,
+            manual: thermostazv.manual,
+            manual_remaining: thermostazv
+                .manual
+                .map(|m| (m.until - Local::now()).num_seconds().max(0)),
+            cycling: thermostazv.cycling,
+            suppressed: thermostazv.guard.suppressed,
+            strategy: thermostazv.strategy,
+            frost: thermostazv.frost,
+            duty: match thermostazv.strategy {
+                Strategy::Hysteresis => None,
+                Strategy::Pid(_) => Some(thermostazv.pid.duty),
+            },
+            temperature: calibrated.map(|(celsius, _)| celsius),
+            humidity: calibrated.map(|(_, rh)| rh),
+            raw,
+            sensor_error,
+            timestamp: Local::now().to_rfc3339(),
+            status_timestamp: status_at.map(|t| t.to_rfc3339()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::SI7021;
+    use serde_json::Value;
+
+    #[test]
+    fn state_json() {
+        let status = Cmd::Status(
+            Relay::Hot,
+            SensorResult::Ok(SensorOk {
+                h: 1 << 19,
+                t: 1 << 19,
+            }),
+            Mode::Host,
+        );
+        let mut thermostazv = Thermostazv::default();
+        thermostazv.read(SI7021, 19.5, Local::now());
+        let state = State::new(&thermostazv, &status, Some(Local::now()));
+        let json: Value = serde_json::to_value(&state).unwrap_or_default();
+        assert_eq!(json["relay"], true);
+        assert_eq!(json["hot"], false);
+        assert_eq!(json["mode"], "Host");
+        assert_eq!(json["raw"]["t"], 1 << 19);
+        assert_eq!(json["temperature"], 50.0);
+        assert_eq!(json["humidity"], 50.0);
+        assert_eq!(json["sensor_error"], Value::Null);
+        assert_eq!(json["schedule"]["morning"], 6);
+        assert_eq!(json["source"], SI7021);
+        assert_eq!(json["current"], 19.5);
+        assert_eq!(json["readings"][0]["used"], true);
+        assert!(json["status_timestamp"].is_string());
+    }
+
+    #[test]
+    fn state_with_sensor_error() {
+        let status = Cmd::Status(Relay::Cold, SensorResult::Err(SensorErr::Bus), Mode::Local);
+        let state = State::new(&Thermostazv::default(), &status, None);
+        assert_eq!(state.sensor_error, Some(SensorErr::Bus));
+        assert_eq!(state.temperature, None);
+        assert_eq!(state.status_timestamp, None);
+    }
+}
@@ -6,6 +6,8 @@ pub type SWatchReceiver = tokio::sync::watch::Receiver<Cmd>;
 pub type SCmdSender = async_channel::Sender<Cmd>;
 pub type SCmdReceiver = async_channel::Receiver<Cmd>;
 
+/// Watch the last `Cmd::Status` of the firmware, and wake the watchers on each one,
+/// even when it did not change: they tell how fresh the reading is
 pub async fn smanager(
     recv_cmd: SCmdReceiver,
     pub_state: SWatchSender,
@@ -15,15 +17,30 @@ pub async fn smanager(
         tokio::select! {
             _ = shutdown_receiver.changed() => return Ok(()),
             new = recv_cmd.recv() => if let Ok(new) = new {
-                pub_state.send_if_modified(|old: &mut Cmd| {
-                    if *old == new {
-                        false
-                    } else {
-                        *old = new;
-                        true
-                    }
-                });
+                pub_state.send_replace(new);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use thermostazv2_lib::{Mode, Relay, SensorErr, SensorResult};
+
+    #[tokio::test]
+    async fn same_status() {
+        let (send_cmd, recv_cmd) = async_channel::unbounded();
+        let (pub_state, mut get_state) = tokio::sync::watch::channel(Cmd::Ping);
+        let (shutdown, shutdown_receiver) = tokio::sync::watch::channel(false);
+        let manager = tokio::spawn(smanager(recv_cmd, pub_state, shutdown_receiver));
+        let status = Cmd::Status(Relay::Cold, SensorResult::Err(SensorErr::Bus), Mode::Host);
+        for _ in 0..2 {
+            assert!(send_cmd.send(status.clone()).await.is_ok());
+            assert!(get_state.changed().await.is_ok());
+            assert_eq!(*get_state.borrow_and_update(), status);
+        }
+        assert!(shutdown.send(true).is_ok());
+        assert!(matches!(manager.await, Ok(Ok(()))));
+    }
+}
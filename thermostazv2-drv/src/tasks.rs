@@ -1,71 +1,243 @@
-use crate::err::ThermostazvResult;
-use crate::sercon::SerialConnection;
+use crate::err::{ThermostazvError, ThermostazvResult};
+use crate::hass::{PRESET, STATE, TARGET};
+use crate::sercon::{discover, handshake, open, understands, Uart};
+use crate::sources::Sources;
+use crate::state::State;
 use crate::status::{SCmdSender, SWatchReceiver};
-use crate::thermostazv::{TCmd, TCmdSender, TWatchReceiver};
-use async_channel::{Receiver, Sender};
+use crate::thermostazv::{Response, TCmd, TCmdSender, TReq, TReqSender, TWatchReceiver};
+use crate::zone::topic;
+use async_channel::{Receiver, RecvError, Sender};
+use chrono::{DateTime, Local};
 use futures::stream;
 use futures::{SinkExt, StreamExt};
 use influxdb2::models::DataPoint;
 use rumqttc::{AsyncClient, Event, EventLoop, Packet, Publish, QoS};
-use serde_json::Value;
 use std::time::Duration;
-use thermostazv2_lib::{Cmd, Relay, SensorResult};
+use thermostazv2_lib::{
+    Capabilities, Cmd, Failsafe, Frame, Identity, NackReason, Relay, SensorResult, Seq,
+};
 use tokio::task::JoinHandle;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
 
-type UartWriter =
-    stream::SplitSink<tokio_util::codec::Framed<tokio_serial::SerialStream, SerialConnection>, Cmd>;
+type UartWriter = stream::SplitSink<Uart, Frame>;
 
-type UartReader =
-    stream::SplitStream<tokio_util::codec::Framed<tokio_serial::SerialStream, SerialConnection>>;
+type UartReader = stream::SplitStream<Uart>;
 
-pub async fn serial_writer(
-    to_uart_receive: Receiver<Cmd>,
+pub type AlertSender = Sender<String>;
+pub type AlertReceiver = Receiver<String>;
+
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+const ACK_RETRIES: usize = 3;
+
+/// How long the firmware may stay silent before we consider the link dead,
+/// it answers the `Get` of `heartbeat` every 10 s
+const LINK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delays between two attempts to reopen the serial port, doubling from min to max
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+/// Wait for the `Ack` or `Nack` of `seq`, dropping stale ones
+async fn wait_ack(acks: &Receiver<Cmd>, seq: Seq) -> Result<Option<NackReason>, RecvError> {
+    loop {
+        match acks.recv().await? {
+            Cmd::Ack(s) if s == seq => return Ok(None),
+            Cmd::Nack(s, reason) if s == seq => return Ok(Some(reason)),
+            ack => tracing::debug!("dropping stale {:?}", ack),
+        }
+    }
+}
+
+/// Send the queued commands the firmware of `identity` understands, until the link breaks
+async fn serial_writer(
+    to_uart_receive: &Receiver<Cmd>,
+    acks: Receiver<Cmd>,
     mut uart_writer: UartWriter,
-    mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
+    identity: &Identity,
+    alert: &AlertSender,
 ) -> ThermostazvResult {
+    let mut seq: Seq = 0;
     loop {
-        tokio::select! {
-            _ = shutdown_receiver.changed() => return Ok(()),
-            cmd = to_uart_receive.recv() => if let Ok(cmd) = cmd {
-                tracing::debug!("sending {:?} to serial", cmd);
-                uart_writer.send(cmd).await?;
+        let cmd = to_uart_receive.recv().await?;
+        if !understands(identity, &cmd) {
+            alert
+                .send(format!(
+                    "{cmd:?} not supported by firmware {} (protocol {})",
+                    identity.firmware, identity.protocol
+                ))
+                .await?;
+            continue;
+        }
+        seq = seq.wrapping_add(1);
+        let frame = Frame { seq, cmd };
+        let mut acked = false;
+        for attempt in 1..=ACK_RETRIES {
+            tracing::debug!("sending {:?} to serial, attempt {}", frame, attempt);
+            uart_writer.send(frame.clone()).await?;
+            match timeout(ACK_TIMEOUT, wait_ack(&acks, seq)).await {
+                Ok(Ok(None)) => {
+                    acked = true;
+                    break;
+                }
+                Ok(Ok(Some(NackReason::Corrupted))) => {
+                    tracing::warn!("{:?} corrupted on the way", frame);
+                }
+                Ok(Ok(Some(reason))) => {
+                    alert
+                        .send(format!("{:?} refused: {reason:?}", frame.cmd))
+                        .await?;
+                    acked = true;
+                    break;
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => tracing::warn!("no ack for {:?}", frame),
             }
         }
+        if !acked {
+            alert
+                .send(format!("{:?} not acknowledged", frame.cmd))
+                .await?;
+        }
     }
 }
 
-pub async fn serial_reader(
+/// Dispatch the frames of the firmware, until the link breaks or goes silent
+async fn serial_reader(
     mut uart_reader: UartReader,
+    to_uart_send: &Sender<Cmd>,
+    acks: Sender<Cmd>,
+    set_status: &SCmdSender,
+    to_mqtt_send: &Sender<Cmd>,
+) -> ThermostazvResult {
+    loop {
+        let Some(frame) = timeout(LINK_TIMEOUT, uart_reader.next()).await? else {
+            return Err(ThermostazvError::Closed.into());
+        };
+        let Frame { cmd, .. } = frame?;
+        tracing::debug!("serial received {:?}", cmd);
+        match cmd {
+            Cmd::Ping => to_uart_send.send(Cmd::Pong).await?,
+            Cmd::Status(..) => set_status.send(cmd).await?,
+            Cmd::Get
+            | Cmd::Set(_)
+            | Cmd::Hello(_)
+            | Cmd::SetFailsafe(_)
+            | Cmd::SetLocal(_)
+            | Cmd::SetMode(_) => {
+                tracing::error!("wrong cmd received: {:?}", cmd);
+            }
+            Cmd::Pong => to_mqtt_send.send(cmd).await?,
+            Cmd::HelloAck(identity) => tracing::warn!("unexpected identity: {:?}", identity),
+            Cmd::Ack(_) | Cmd::Nack(_, _) => acks.send(cmd).await?,
+        }
+    }
+}
+
+/// Own the serial link: find the board, open its port, shake hands, configure the failsafe and tell
+/// `TManager` on `connected`, then run until EOF, an error or a silence, and start
+/// again after a growing delay.
+#[allow(clippy::too_many_arguments)]
+pub async fn serial_link(
+    port: Option<String>,
+    serial: Option<String>,
+    baud: u32,
+    failsafe: Failsafe,
     to_uart_send: Sender<Cmd>,
+    to_uart_receive: Receiver<Cmd>,
     set_status: SCmdSender,
     to_mqtt_send: Sender<Cmd>,
+    connected: Sender<Identity>,
+    alert: AlertSender,
     mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 ) -> ThermostazvResult {
+    let mut delay = RECONNECT_MIN;
+    let mut down = false;
     loop {
-        tokio::select! {
+        let link = tokio::select! {
             _ = shutdown_receiver.changed() => return Ok(()),
-            cmd = uart_reader.next() => if let Some(Ok(cmd)) = cmd {
-                tracing::debug!("serial received {:?}", cmd);
-                match cmd {
-                    Cmd::Ping => to_uart_send.send(Cmd::Pong).await?,
-                    Cmd::Status(r, s) => set_status.send(Cmd::Status(r, s)).await?,
-                    Cmd::Get | Cmd::Set(_) => tracing::error!("wrong cmd received: {:?}", cmd),
-                    Cmd::Pong => to_mqtt_send.send(cmd).await?,
+            link = async {
+                let port = match port {
+                    Some(ref port) => port.clone(),
+                    None => discover(serial.as_deref())?,
+                };
+                let mut uart = open(&port, baud)?;
+                let identity = handshake(&mut uart).await?;
+                Ok::<_, ThermostazvError>((uart, identity))
+            } => link,
+        };
+        let res = match link {
+            Ok((uart, identity)) => {
+                delay = RECONNECT_MIN;
+                if down {
+                    alert
+                        .send(format!("serial link up, firmware {}", identity.firmware))
+                        .await?;
+                    down = false;
+                }
+                // queued while the link was down, `TManager` sends what still matters
+                while to_uart_receive.try_recv().is_ok() {}
+                if identity.capabilities.contains(Capabilities::FAILSAFE) {
+                    to_uart_send.send(Cmd::SetFailsafe(failsafe)).await?;
+                } else {
+                    tracing::warn!("firmware has no failsafe");
                 }
+                connected.send(identity.clone()).await?;
+                let (uart_writer, uart_reader) = uart.split();
+                let (acks_send, acks_receive) = async_channel::unbounded();
+                tokio::select! {
+                    _ = shutdown_receiver.changed() => return Ok(()),
+                    res = serial_writer(&to_uart_receive, acks_receive, uart_writer, &identity, &alert) => res,
+                    res = serial_reader(uart_reader, &to_uart_send, acks_send, &set_status, &to_mqtt_send) => res,
+                }
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            if down {
+                tracing::warn!("serial link still down: {}", e);
+            } else {
+                alert.send(format!("serial link down: {e}")).await?;
+                down = true;
             }
         }
+        tokio::select! {
+            _ = shutdown_receiver.changed() => return Ok(()),
+            () = sleep(delay) => delay = (delay * 2).min(RECONNECT_MAX),
+        }
+    }
+}
+
+/// Keep the firmware failsafe from kicking in while we are alive
+pub async fn heartbeat(
+    to_uart_send: Sender<Cmd>,
+    mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
+) -> ThermostazvResult {
+    loop {
+        tokio::select! {
+            _ = shutdown_receiver.changed() => return Ok(()),
+            () = sleep(Duration::from_secs(10)) => to_uart_send.send(Cmd::Get).await?,
+        }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn mqtt_receive(
     to_uart_send: Sender<Cmd>,
     from_mqtt_receive: Receiver<Publish>,
     set_thermostazv: TCmdSender,
+    req_thermostazv: TReqSender,
     get_status: SWatchReceiver,
     to_mqtt_send: Sender<Cmd>,
+    to_response: Sender<Response>,
+    sources: Sources,
+    zone: String,
     mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 ) -> ThermostazvResult {
+    let cmd_topic = topic(&zone, "cmd");
+    let set_topic = topic(&zone, "set");
+    let target_topic = topic(&zone, &format!("{TARGET}/set"));
+    let preset_topic = topic(&zone, &format!("{PRESET}/set"));
+    let presence_topic = topic(&zone, "presence");
     loop {
         tokio::select! {
             _ = shutdown_receiver.changed() => return Ok(()),
@@ -73,26 +245,48 @@ pub async fn mqtt_receive(
                 tracing::info!("mqtt received {:?}", msg);
                 let topic = msg.topic;
                 let cmd = msg.payload;
-                if topic == "/azv/thermostazv/cmd" {
+                if topic == cmd_topic {
                     if cmd == "c" {
-                        to_uart_send.send(Cmd::Set(Relay::Hot)).await?;
+                        set_thermostazv.send(TCmd::SetHot(true)).await?;
                     } else if cmd == "f" {
-                        to_uart_send.send(Cmd::Set(Relay::Cold)).await?;
+                        set_thermostazv.send(TCmd::SetHot(false)).await?;
                     } else if cmd == "s" {
-                        let status = *get_status.borrow();
+                        let status = get_status.borrow().clone();
                         to_mqtt_send.send(status).await?;
                     } else if cmd == "p" {
                         to_uart_send.send(Cmd::Ping).await?;
+                    } else if cmd == "a" {
+                        set_thermostazv.send(TCmd::SetLocal(true)).await?;
+                    } else if cmd == "h" {
+                        set_thermostazv.send(TCmd::SetLocal(false)).await?;
+                    }
+                } else if topic == set_topic {
+                    let response = match serde_json::from_slice(&cmd) {
+                        Ok(cmd) => {
+                            let (reply, response) = tokio::sync::oneshot::channel();
+                            req_thermostazv.send(TReq { cmd, reply }).await?;
+                            response.await?
+                        }
+                        Err(e) => Response::Error(format!("Invalid command: {e}")),
+                    };
+                    to_response.send(response).await?;
+                } else if topic == target_topic {
+                    if let Ok(Ok(target)) = std::str::from_utf8(&cmd).map(str::parse) {
+                        set_thermostazv.send(TCmd::SetTarget(target)).await?;
+                    } else {
+                        tracing::warn!("invalid target {:?}", cmd);
                     }
-                } else if topic == "/azv/thermostazv/presence" {
+                } else if topic == preset_topic {
+                    set_thermostazv.send(TCmd::SetPresent(cmd != "away")).await?;
+                } else if topic == presence_topic {
                     set_thermostazv
                         .send(TCmd::SetPresent(cmd == "présent"))
                         .await?;
-                } else if topic == "tele/tasmota_43D8FD/SENSOR" {
-                    let decoded: Value = serde_json::from_slice(&cmd)?;
-                    if let Value::Number(temperature) = &decoded["SI7021"]["Temperature"] {
-                        if let Some(temp) = temperature.as_f64() {
-                            set_thermostazv.send(TCmd::Current(temp)).await?;
+                } else {
+                    for source in &sources {
+                        if let Some(value) = source.parse(&topic, &cmd) {
+                            let source = source.name.clone();
+                            set_thermostazv.send(TCmd::Current { source, value }).await?;
                         }
                     }
                 }
@@ -101,102 +295,213 @@ pub async fn mqtt_receive(
     }
 }
 
+/// Publish `payload` on `topic`, logging a failure: the next publications may go through
+async fn publish(
+    client: &AsyncClient,
+    topic: String,
+    retain: bool,
+    payload: impl Into<Vec<u8>> + Send,
+) {
+    if let Err(e) = client
+        .publish(&topic, QoS::AtLeastOnce, retain, payload)
+        .await
+    {
+        tracing::error!("mqtt publish on {} failed: {}", topic, e);
+    }
+}
+
+/// Publish the retained JSON `State` of `zone`
+async fn publish_state(
+    client: &AsyncClient,
+    zone: &str,
+    get_thermostazv: &TWatchReceiver,
+    get_status: &SWatchReceiver,
+    status_at: Option<DateTime<Local>>,
+) {
+    let state = State::new(&get_thermostazv.borrow(), &get_status.borrow(), status_at);
+    match serde_json::to_vec(&state) {
+        Ok(state) => publish(client, topic(zone, STATE), true, state).await,
+        Err(e) => tracing::error!("state not serialized: {}", e),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn mqtt_publish(
     to_mqtt_receive: Receiver<Cmd>,
-    get_thermostazv: TWatchReceiver,
+    alert: AlertReceiver,
+    response: Receiver<Response>,
+    mut get_thermostazv: TWatchReceiver,
+    mut get_status: SWatchReceiver,
     client: AsyncClient,
+    text_log: bool,
+    zone: String,
     mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 ) -> ThermostazvResult {
+    let mut status_at = None;
+    publish_state(&client, &zone, &get_thermostazv, &get_status, status_at).await;
     loop {
         tokio::select! {
             _ = shutdown_receiver.changed() => return Ok(()),
+            Ok(()) = get_thermostazv.changed() => {
+                publish_state(&client, &zone, &get_thermostazv, &get_status, status_at).await;
+            },
+            Ok(()) = get_status.changed() => {
+                status_at = Some(Local::now());
+                publish_state(&client, &zone, &get_thermostazv, &get_status, status_at).await;
+            },
+            msg = alert.recv() => if let Ok(msg) = msg {
+                tracing::error!("alert: {}", msg);
+                publish(&client, topic(&zone, "alert"), false, msg).await;
+            },
+            msg = response.recv() => if let Ok(msg) = msg {
+                match serde_json::to_string(&msg) {
+                    Ok(msg) => publish(&client, topic(&zone, "response"), false, msg).await,
+                    Err(e) => tracing::error!("response not serialized: {}", e),
+                }
+            },
             cmd = to_mqtt_receive.recv() => if let Ok(cmd) = cmd {
+                if !text_log {
+                    if let Cmd::Status(..) = cmd {
+                        publish_state(&client, &zone, &get_thermostazv, &get_status, status_at).await;
+                    }
+                    continue;
+                }
                 let msg = match cmd {
-                    Cmd::Get | Cmd::Ping => {
+                    Cmd::Get
+                    | Cmd::Ping
+                    | Cmd::Hello(_)
+                    | Cmd::HelloAck(_)
+                    | Cmd::Ack(_)
+                    | Cmd::Nack(_, _)
+                    | Cmd::SetFailsafe(_)
+                    | Cmd::SetLocal(_)
+                    | Cmd::SetMode(_) => {
                         tracing::error!("wrong command to publish to MQTT");
                         None
                     }
                     Cmd::Set(Relay::Hot) => Some("allumage du chauffe-eau".to_string()),
                     Cmd::Set(Relay::Cold) => Some("extinction du chauffe-eau".to_string()),
                     Cmd::Pong => Some("pong".to_string()),
-                    Cmd::Status(relay, sensor) => Some(format!(
-                        "présent: {}, relay: {:?}, garage: {}",
-                        get_thermostazv.borrow().present,
+                    Cmd::Status(relay, sensor, mode) => Some(format!(
+                        "présent: {}, relay: {:?}, garage: {}, mode: {:?}",
+                        get_thermostazv.borrow().thermostat.present,
                         relay,
                         match sensor {
-                            SensorResult::Ok(s) => format!("{}°C, {}%", s.celsius(), s.rh()),
+                            SensorResult::Ok(s) => {
+                                let (celsius, rh) = get_thermostazv.borrow().calibrated_aht20(s);
+                                format!("{celsius}°C, {rh}%")
+                            }
                             SensorResult::Err(e) => format!("error {e:?}"),
-                        }
+                        },
+                        mode,
                     )),
                 };
 
                 if let Some(msg) = msg {
-                    client
-                        .publish("/azv/thermostazv/log", QoS::AtLeastOnce, false, msg)
-                        .await?;
+                    publish(&client, topic(&zone, "log"), false, msg).await;
                 }
             }
         }
     }
 }
 
+/// Points of `zone` for influx
+fn points(
+    get_thermostazv: &TWatchReceiver,
+    get_status: &SWatchReceiver,
+    zone: &str,
+) -> anyhow::Result<Vec<DataPoint>> {
+    let mut points = vec![];
+    let (current, readings) = get_thermostazv.borrow().current(Local::now());
+    let mut point = {
+        let thermostazv = get_thermostazv.borrow();
+        DataPoint::builder("azviot")
+            .tag("device", zone)
+            .field("relay", thermostazv.thermostat.hot)
+            .field("absent", !thermostazv.thermostat.present)
+            .field("targetf", thermostazv.hysteresis(Local::now()))
+            .field("suppressed", i64::from(thermostazv.guard.suppressed))
+    };
+    if let Some(current) = current {
+        point = point.field("current", current);
+    }
+    points.push(point.build()?);
+    for reading in readings {
+        points.push(
+            DataPoint::builder("azviot")
+                .tag("device", zone)
+                .tag("source", reading.source)
+                .field("reading", reading.celsius)
+                .field("used", reading.used)
+                .field("outlier", reading.outlier)
+                .build()?,
+        );
+    }
+    let mut temperature = None;
+    let mut humidity = None;
+    {
+        if let Cmd::Status(_, SensorResult::Ok(sensor), _) = *get_status.borrow() {
+            let (celsius, rh) = get_thermostazv.borrow().calibrated_aht20(sensor);
+            temperature = Some(celsius);
+            humidity = Some(rh);
+        }
+    }
+
+    if let (Some(temperature), Some(humidity)) = (temperature, humidity) {
+        points.push(
+            DataPoint::builder("azviot")
+                .tag("device", zone)
+                .field("Temperature", temperature)
+                .field("Humidity", humidity)
+                .build()?,
+        );
+    }
+    Ok(points)
+}
+
+/// Write the points of `zone` every 5 minutes, a failed write is logged and skipped
 pub async fn influx(
     client: influxdb2::Client,
     get_thermostazv: TWatchReceiver,
     get_status: SWatchReceiver,
     infl_buck: &str,
+    zone: &str,
     mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 ) -> ThermostazvResult {
     loop {
         tokio::select! {
             _ = shutdown_receiver.changed() => return Ok(()),
             _ = sleep(Duration::from_secs(300)) => {
-                let mut points = vec![];
-                {
-                    let thermostazv = get_thermostazv.borrow();
-                    points.push(
-                        DataPoint::builder("azviot")
-                            .tag("device", "thermostazv")
-                            .field("relay", thermostazv.hot)
-                            .field("absent", !thermostazv.present)
-                            .field("targetf", thermostazv.hysteresis())
-                            .build()?,
-                    );
-                }
-                let mut temperature = None;
-                let mut humidity = None;
-                {
-                    if let Cmd::Status(_, SensorResult::Ok(sensor)) = *get_status.borrow() {
-                        temperature = Some(sensor.celsius());
-                        humidity = Some(sensor.rh());
-                    }
-                }
-
-                if let (Some(temperature), Some(humidity)) = (temperature, humidity) {
-                    points.push(
-                        DataPoint::builder("azviot")
-                            .tag("device", "thermostazv")
-                            .field("Temperature", temperature)
-                            .field("Humidity", humidity)
-                            .build()?,
-                    );
+                let written = match points(&get_thermostazv, &get_status, zone) {
+                    Ok(points) => client
+                        .write(infl_buck, stream::iter(points))
+                        .await
+                        .map_err(anyhow::Error::from),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = written {
+                    tracing::error!("influx write failed: {}", e);
                 }
-                client.write(infl_buck, stream::iter(points)).await?;
             }
         }
     }
 }
 
+/// Poll the MQTT connection, handing every message to each zone
 pub async fn mqtt_connection(
     mut connection: EventLoop,
-    from_mqtt_send: Sender<Publish>,
+    from_mqtt_send: Vec<Sender<Publish>>,
     mut shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 ) -> ThermostazvResult {
     loop {
         tokio::select! {
             _ = shutdown_receiver.changed() => return Ok(()),
             res = connection.poll() => match res {
-                Ok(Event::Incoming(Packet::Publish(p))) => from_mqtt_send.send(p).await?,
+                Ok(Event::Incoming(Packet::Publish(p))) => for zone in &from_mqtt_send {
+                    if zone.send(p.clone()).await.is_err() {
+                        tracing::error!("zone gone, dropping {:?}", p.topic);
+                    }
+                },
                 Err(n) => tracing::error!("incoming mqtt packet Err:  {:?}", n),
                 Ok(_) => {}
             }
@@ -1,10 +1,74 @@
+use crate::calibration;
 use crate::err::{ThermostazvError, ThermostazvResult};
-use async_channel::Sender;
-use chrono::{Local, Timelike};
+use crate::sources::{self, Fusion, Input, Reading, Sources};
+use crate::status::SWatchReceiver;
+use crate::tasks::AlertSender;
+use crate::zone::DEFAULT_ZONE;
+use async_channel::{Receiver, Sender};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use heapless::Vec;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
-use thermostazv2_lib::{Cmd, Relay};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thermostazv2_lib::control::{
+    self, CycleGuard, Cycling, Pid, PidGains, Slot, Strategy, Thermostat, WeekTime, BAND,
+    MAX_SWITCHES, SLOTS,
+};
+use thermostazv2_lib::{
+    Capabilities, Cmd, Identity, Mode, Relay, SensorOk, SensorResult, Setpoint,
+};
+use tokio::time::Instant;
+
+/// How long the relay reported by the firmware may differ from the requested one
+const RELAY_MISMATCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Accepted setpoints, in °C
+pub const SETPOINTS: RangeInclusive<f64> = 5.0..=30.0;
+
+/// Accepted temperature readings, in °C
+const READINGS: RangeInclusive<f64> = -40.0..=85.0;
+
+/// How often `TManager` looks at the clock
+const TICK: Duration = Duration::from_secs(60);
+
+/// Heating rate before we measure one, in °C per hour
+const DEFAULT_HEATING_RATE: f64 = 1.0;
+
+/// Believable heating rates, in °C per hour
+const HEATING_RATES: RangeInclusive<f64> = 0.1..=10.0;
+
+/// Weight of a new measure in the heating rate estimate
+const HEATING_RATE_WEIGHT: f64 = 0.3;
+
+/// Shortest heating run worth measuring, in hours
+const MIN_HEATING_RUN: f64 = 0.25;
+
+/// Longest anticipation of a return from away mode, in hours
+const MAX_LEAD: f64 = 12.0;
+
+/// Accepted boost durations, in minutes
+const BOOSTS: RangeInclusive<u32> = 1..=24 * 60;
+
+/// Accepted hysteresis half widths, in °C
+const BANDS: RangeInclusive<f64> = 0.05..=5.0;
+
+/// Accepted frost protection floors, in °C
+const FROST_FLOORS: RangeInclusive<f64> = 0.0..=12.0;
+
+/// Accepted PID cycle periods, in seconds
+const PID_PERIODS: RangeInclusive<u32> = 60..=3600;
+
+/// `Strategy` in the config and the commands, tagged by `kind`
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Strategy", rename_all = "lowercase", tag = "kind")]
+#[allow(dead_code)] // only built through `Strategy`
+pub enum StrategyDef {
+    Hysteresis,
+    Pid(PidGains),
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum TCmd {
@@ -15,39 +79,216 @@ pub enum TCmd {
     SetEvening(u32),
     SetPresent(bool),
     SetHot(bool),
-    Current(f64),
+    /// Reading of a source, before calibration
+    Current {
+        source: String,
+        value: f64,
+    },
+    SetLocal(bool),
+    /// Change the setpoint in use right now: day, night or empty
+    SetTarget(f64),
+    /// Replace the weekly schedule, an empty one goes back to `morning` and `evening`
+    SetSchedule(Box<Vec<Slot, SLOTS>>),
+    /// Hold `empty` until we come back, or cancel with `None`
+    SetAwayUntil(Option<DateTime<Local>>),
+    /// Heat for that many minutes, whatever the temperature
+    Boost(u32),
+    /// Hold `target` until `until`, or until the next schedule change
+    Hold {
+        target: f64,
+        until: Option<DateTime<Local>>,
+    },
+    /// Back to the schedule
+    CancelManual,
+    /// Change the limits protecting the relay from short cycles
+    SetCycling(Cycling),
+    /// Choose how to drive the relay
+    SetStrategy(#[serde(with = "StrategyDef")] Strategy),
+    /// Change the half width of the hysteresis, in °C
+    SetBand(f64),
+    /// Correct the readings of a sensor
+    SetCalibration {
+        sensor: String,
+        calibration: calibration::Sensor,
+    },
+    /// Change the frost protection
+    SetFrost(Frost),
+    /// Change how the readings of the sources are combined
+    SetFusion(Fusion),
+}
+
+/// Protection of the house against frost, whatever the mode
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Frost {
+    /// lowest target, in °C
+    pub floor: f64,
+    /// minutes without reading before a sensor is not trusted anymore
+    pub stale: u32,
+    /// heat `burst` minutes every `every` minutes while the temperature is unknown
+    pub burst: u32,
+    pub every: u32,
+}
+
+impl Default for Frost {
+    fn default() -> Self {
+        Self {
+            floor: 5.0,
+            stale: 60,
+            burst: 10,
+            every: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase", tag = "kind", content = "target")]
+pub enum ManualMode {
+    /// relay on
+    Boost,
+    /// regulate around this setpoint
+    Hold(f64),
+}
+
+/// Manual override of the schedule, until it expires
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Manual {
+    pub mode: ManualMode,
+    pub until: DateTime<Local>,
+}
+
+const fn default_heating_rate() -> f64 {
+    DEFAULT_HEATING_RATE
+}
+
+const fn default_band() -> f64 {
+    BAND
+}
+
+fn default_zone() -> String {
+    DEFAULT_ZONE.to_string()
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Thermostazv {
-    pub day: f64,
-    pub night: f64,
-    pub empty: f64,
-    pub morning: u32,
-    pub evening: u32,
-    pub present: bool,
-    pub hot: bool,
+    #[serde(flatten)]
+    pub thermostat: Thermostat,
+    /// let the firmware regulate by itself
+    #[serde(default)]
+    pub local: bool,
+    /// away mode: hold `empty` until then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub away_until: Option<DateTime<Local>>,
+    /// measured heating rate, in °C per hour
+    #[serde(default = "default_heating_rate")]
+    pub heating_rate: f64,
+    /// manual override of the schedule
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manual: Option<Manual>,
+    /// relay protection against short cycles
+    #[serde(default)]
+    pub cycling: Cycling,
+    /// recent switches of the relay
+    #[serde(skip)]
+    pub guard: CycleGuard,
+    /// how to drive the relay
+    #[serde(default, with = "StrategyDef")]
+    pub strategy: Strategy,
+    /// state of the PID strategy
+    #[serde(skip)]
+    pub pid: Pid,
+    /// half width of the hysteresis, in °C
+    #[serde(default = "default_band")]
+    pub band: f64,
+    /// corrections of the readings, by sensor
+    #[serde(default)]
+    pub calibration: BTreeMap<String, calibration::Sensor>,
+    /// protection against frost
+    #[serde(default)]
+    pub frost: Frost,
+    /// temperature sensors
+    #[serde(
+        default = "sources::defaults",
+        deserialize_with = "sources::deserialize"
+    )]
+    pub sources: Sources,
+    /// how to combine the readings of the sources
+    #[serde(default)]
+    pub fusion: Fusion,
+    /// last calibrated reading of each source, and when it was taken
+    #[serde(skip)]
+    pub readings: BTreeMap<String, (f64, DateTime<Local>)>,
+    /// when we started waiting for the first reading
+    #[serde(skip)]
+    pub started: Option<DateTime<Local>>,
+    /// source of the last decision of `update`, `None` once they are all stale
+    #[serde(skip)]
+    pub source: Option<String>,
+    /// start time and temperature of the current heating run
+    #[serde(skip)]
+    heating_since: Option<(DateTime<Local>, f64)>,
+    /// zone we regulate, which names the config file
+    #[serde(skip, default = "default_zone")]
+    pub zone: String,
 }
 
-pub type TWatchSender = tokio::sync::watch::Sender<Thermostazv>;
-pub type TWatchReceiver = tokio::sync::watch::Receiver<Thermostazv>;
-pub type TCmdSender = async_channel::Sender<TCmd>;
-pub type TCmdReceiver = async_channel::Receiver<TCmd>;
-
 impl Default for Thermostazv {
     fn default() -> Self {
         Self {
-            day: 17.5,
-            night: 17.0,
-            empty: 10.0,
-            morning: 6,
-            evening: 22,
-            present: true,
-            hot: false,
+            thermostat: Thermostat::default(),
+            local: false,
+            away_until: None,
+            heating_rate: DEFAULT_HEATING_RATE,
+            manual: None,
+            cycling: Cycling::default(),
+            guard: CycleGuard::default(),
+            strategy: Strategy::default(),
+            pid: Pid::default(),
+            band: BAND,
+            calibration: BTreeMap::new(),
+            frost: Frost::default(),
+            sources: sources::defaults(),
+            fusion: Fusion::default(),
+            readings: BTreeMap::new(),
+            started: None,
+            source: None,
+            heating_since: None,
+            zone: default_zone(),
         }
     }
 }
 
+/// Answer to a `TReq`
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+    State(Box<Thermostazv>),
+    Error(String),
+}
+
+/// A `TCmd` which wants the resulting state back
+#[derive(Debug)]
+pub struct TReq {
+    pub cmd: TCmd,
+    pub reply: tokio::sync::oneshot::Sender<Response>,
+}
+
+pub type TWatchSender = tokio::sync::watch::Sender<Thermostazv>;
+pub type TWatchReceiver = tokio::sync::watch::Receiver<Thermostazv>;
+pub type TCmdSender = async_channel::Sender<TCmd>;
+pub type TCmdReceiver = async_channel::Receiver<TCmd>;
+pub type TReqSender = async_channel::Sender<TReq>;
+pub type TReqReceiver = async_channel::Receiver<TReq>;
+
+/// Seconds since the epoch, for `CycleGuard`
+fn seconds(t: DateTime<Local>) -> u64 {
+    u64::try_from(t.timestamp()).unwrap_or_default()
+}
+
+/// Where `t` falls in the week
+pub fn week_time(t: DateTime<Local>) -> WeekTime {
+    WeekTime::new(t.weekday().num_days_from_monday(), t.hour(), t.minute())
+}
+
 fn config_path() -> Box<Path> {
     directories::ProjectDirs::from("", "", "thermostazv2").map_or_else(
         || Path::new("/tmp").into(),
@@ -55,52 +296,385 @@ fn config_path() -> Box<Path> {
     )
 }
 
+/// Config file of `zone`, the default one keeps the file of the single zone days
+pub fn config_file(zone: &str) -> PathBuf {
+    if zone == DEFAULT_ZONE {
+        config_path().join("config.toml")
+    } else {
+        config_path().join(format!("{zone}.toml"))
+    }
+}
+
 impl Thermostazv {
-    pub fn new() -> Result<Self, ThermostazvError> {
+    pub fn new(zone: &str) -> Result<Self, ThermostazvError> {
         let path = config_path();
         if !path.exists() {
             fs::create_dir_all(&path)?;
         }
-        let path = path.join("config.toml");
-        Ok(if path.exists() {
+        let path = config_file(zone);
+        let mut thermostazv: Self = if path.exists() {
             let read = fs::read_to_string(path)?;
             toml::from_str(&read)?
         } else {
             Self::default()
-        })
+        };
+        thermostazv.zone = zone.to_string();
+        Ok(thermostazv)
+    }
+
+    /// Serialize through a `Value`, which puts the schedule tables after the plain values
+    fn to_toml(&self) -> Result<String, ThermostazvError> {
+        Ok(toml::to_string(&toml::Value::try_from(self)?)?)
     }
 
     pub fn save(&self) -> ThermostazvResult {
-        let toml = toml::to_string(&self)?;
-        fs::write(config_path().join("config.toml"), toml)?;
+        let toml = self.to_toml()?;
+        fs::write(config_file(&self.zone), toml)?;
         Ok(())
     }
 
-    fn target(&self) -> f64 {
-        if self.present {
-            let now = Local::now();
-            if self.morning <= now.hour() && now.hour() < self.evening {
-                self.day
+    /// Check that `cmd` makes sense with the current state
+    pub fn validate(&self, cmd: &TCmd) -> Result<(), ThermostazvError> {
+        let in_range = |name: &str, val: f64, range: RangeInclusive<f64>| {
+            if range.contains(&val) {
+                Ok(())
             } else {
-                self.night
+                Err(ThermostazvError::Invalid(format!(
+                    "{name} {val} is not in {range:?}"
+                )))
             }
-        } else {
-            self.empty
+        };
+        match *cmd {
+            TCmd::SetDay(val) => in_range("day", val, SETPOINTS),
+            TCmd::SetNight(val) => in_range("night", val, SETPOINTS),
+            TCmd::SetEmpty(val) => in_range("empty", val, SETPOINTS),
+            TCmd::SetTarget(val) => in_range("target", val, SETPOINTS),
+            TCmd::Current { ref source, .. } if !self.has_source(source) => Err(
+                ThermostazvError::Invalid(format!("unknown source {source}")),
+            ),
+            TCmd::Current { value, .. } => in_range("current", value, READINGS),
+            TCmd::SetMorning(val) if val >= self.thermostat.evening => {
+                Err(ThermostazvError::Invalid(format!(
+                    "morning {val} is not before evening {}",
+                    self.thermostat.evening
+                )))
+            }
+            TCmd::SetEvening(val) if val <= self.thermostat.morning || val > 24 => {
+                Err(ThermostazvError::Invalid(format!(
+                    "evening {val} is not between morning {} and 24",
+                    self.thermostat.morning
+                )))
+            }
+            TCmd::SetAwayUntil(Some(until)) if until <= Local::now() => Err(
+                ThermostazvError::Invalid(format!("away until {until} is in the past")),
+            ),
+            TCmd::Boost(minutes) if !BOOSTS.contains(&minutes) => Err(ThermostazvError::Invalid(
+                format!("boost of {minutes} minutes is not in {BOOSTS:?}"),
+            )),
+            TCmd::Hold {
+                until: Some(until), ..
+            } if until <= Local::now() => Err(ThermostazvError::Invalid(format!(
+                "hold until {until} is in the past"
+            ))),
+            TCmd::Hold { target, .. } => in_range("hold", target, SETPOINTS),
+            TCmd::SetCycling(cycling) if cycling.max_per_hour as usize > MAX_SWITCHES => {
+                Err(ThermostazvError::Invalid(format!(
+                    "{} switches per hour is more than {MAX_SWITCHES}",
+                    cycling.max_per_hour
+                )))
+            }
+            TCmd::SetCycling(cycling) => match self.strategy {
+                Strategy::Pid(gains) if !cycling.fits(gains.period) => {
+                    Err(ThermostazvError::Invalid(format!(
+                        "PID period {} is too short for {cycling:?}",
+                        gains.period
+                    )))
+                }
+                _ => Ok(()),
+            },
+            TCmd::SetStrategy(Strategy::Pid(gains)) => {
+                in_range("kp", gains.kp, 0.0..=f64::MAX)?;
+                in_range("ki", gains.ki, 0.0..=f64::MAX)?;
+                in_range("kd", gains.kd, 0.0..=f64::MAX)?;
+                if !PID_PERIODS.contains(&gains.period) {
+                    Err(ThermostazvError::Invalid(format!(
+                        "period {} is not in {PID_PERIODS:?}",
+                        gains.period
+                    )))
+                } else if !self.cycling.fits(gains.period) {
+                    Err(ThermostazvError::Invalid(format!(
+                        "period {} is too short for {:?}",
+                        gains.period, self.cycling
+                    )))
+                } else {
+                    Ok(())
+                }
+            }
+            TCmd::SetBand(val) => in_range("band", val, BANDS),
+            TCmd::SetCalibration { ref sensor, .. } if !self.has_source(sensor) => Err(
+                ThermostazvError::Invalid(format!("unknown sensor {sensor}")),
+            ),
+            TCmd::SetCalibration {
+                ref calibration, ..
+            } if !(calibration.temperature.is_valid() && calibration.humidity.is_valid()) => Err(
+                ThermostazvError::Invalid(format!("invalid calibration {calibration:?}")),
+            ),
+            TCmd::SetFrost(frost) if frost.stale == 0 || frost.burst >= frost.every => Err(
+                ThermostazvError::Invalid(format!("invalid frost protection {frost:?}")),
+            ),
+            TCmd::SetFrost(frost) => in_range("frost floor", frost.floor, FROST_FLOORS),
+            TCmd::SetFusion(fusion) => in_range("outlier", fusion.outlier, 0.0..=f64::MAX),
+            TCmd::SetSchedule(ref slots) => slots.iter().try_for_each(|slot| {
+                if slot.days.is_empty() {
+                    Err(ThermostazvError::Invalid(format!(
+                        "slot {slot:?} has no day"
+                    )))
+                } else {
+                    in_range("slot target", slot.target, SETPOINTS)
+                }
+            }),
+            _ => Ok(()),
         }
     }
 
-    pub fn hysteresis(&self) -> f64 {
-        self.target() + if self.hot { 0.5 } else { -0.5 }
+    /// Setpoint in use at `now`, never below the frost protection
+    pub fn target(&self, now: DateTime<Local>) -> f64 {
+        let target = match self.manual {
+            Some(Manual {
+                mode: ManualMode::Hold(target),
+                ..
+            }) => target,
+            _ => self.thermostat.target(week_time(now)),
+        };
+        target.max(self.frost.floor)
     }
 
-    pub fn update(&mut self, current: f64) -> bool {
-        let h = self.hysteresis();
-        if self.hot == (current <= h) {
-            false
-        } else {
-            self.hot = current <= h;
-            tracing::info!("temperature: {} / {} => chauffe: {}", current, h, self.hot);
+    fn has_source(&self, name: &str) -> bool {
+        self.sources.iter().any(|source| source.name == name)
+    }
+
+    /// Names of the sources fed by `Cmd::Status`
+    pub fn aht20_sources(&self) -> impl Iterator<Item = &str> {
+        self.sources
+            .iter()
+            .filter(|source| source.input == Input::Aht20)
+            .map(|source| source.name.as_str())
+    }
+
+    /// Record a calibrated reading of `source`, taken `at`
+    pub fn read(&mut self, source: &str, celsius: f64, at: DateTime<Local>) {
+        self.readings.insert(source.to_string(), (celsius, at));
+    }
+
+    /// Whether a reading taken `at` is too old to be trusted
+    fn expired(&self, at: DateTime<Local>, now: DateTime<Local>) -> bool {
+        now - at > chrono::Duration::minutes(i64::from(self.frost.stale))
+    }
+
+    /// Fresh readings of the sources, in their order
+    pub fn fresh(&self, now: DateTime<Local>) -> std::vec::Vec<Reading> {
+        self.sources
+            .iter()
+            .filter_map(|source| {
+                let &(celsius, at) = self.readings.get(&source.name)?;
+                (!self.expired(at, now)).then(|| Reading {
+                    source: source.name.clone(),
+                    celsius,
+                    at,
+                    priority: source.priority,
+                    weight: source.weight,
+                    outlier: false,
+                    used: false,
+                })
+            })
+            .collect()
+    }
+
+    /// Control temperature fused from the fresh readings, and these readings
+    pub fn current(&self, now: DateTime<Local>) -> (Option<f64>, std::vec::Vec<Reading>) {
+        let mut readings = self.fresh(now);
+        (self.fusion.fuse(&mut readings), readings)
+    }
+
+    /// Whether every source is silent for too long: the frost protection has the relay
+    pub fn stale(&self, now: DateTime<Local>) -> bool {
+        self.current(now).0.is_none() && matches!(self.started, Some(at) if self.expired(at, now))
+    }
+
+    /// Whether the frost protection heats at `now`, when the temperature is unknown
+    fn frost_burst(&self, now: DateTime<Local>) -> bool {
+        (seconds(now) / 60) % u64::from(self.frost.every.max(1)) < u64::from(self.frost.burst)
+    }
+
+    /// Temperature at which the relay switches at `now`, or the target with PID
+    pub fn hysteresis(&self, now: DateTime<Local>) -> f64 {
+        match self.strategy {
+            Strategy::Hysteresis => {
+                control::threshold(self.target(now), self.band, self.thermostat.hot)
+            }
+            Strategy::Pid(_) => self.target(now),
+        }
+    }
+
+    /// Corrected temperature and humidity of `sensor`
+    pub fn calibrated(&self, sensor: &str, celsius: f64, rh: f64) -> (f64, f64) {
+        self.calibration
+            .get(sensor)
+            .map_or((celsius, rh), |c| c.apply(celsius, rh))
+    }
+
+    /// Corrections of the AHT20 of the board, under the name of its first source
+    fn aht20_calibration(&self) -> Option<&calibration::Sensor> {
+        self.aht20_sources()
+            .next()
+            .and_then(|name| self.calibration.get(name))
+    }
+
+    /// Corrected temperature and humidity of the AHT20 of the board
+    pub fn calibrated_aht20(&self, sensor: SensorOk) -> (f64, f64) {
+        let (celsius, rh) = (sensor.celsius(), sensor.rh());
+        self.aht20_calibration()
+            .map_or((celsius, rh), |c| c.apply(celsius, rh))
+    }
+
+    /// Setpoint for the firmware, which regulates on the raw AHT20 readings
+    pub fn firmware_setpoint(&self, target: f64) -> Setpoint {
+        self.aht20_calibration().map_or_else(
+            || Setpoint::from_celsius(target, self.band),
+            |c| {
+                Setpoint::from_celsius(
+                    c.temperature.invert(target),
+                    self.band / c.temperature.scale.abs(),
+                )
+            },
+        )
+    }
+
+    pub const fn boosting(&self) -> bool {
+        matches!(
+            self.manual,
+            Some(Manual {
+                mode: ManualMode::Boost,
+                ..
+            })
+        )
+    }
+
+    /// When the schedule changes next
+    pub fn next_change(&self, now: DateTime<Local>) -> DateTime<Local> {
+        let minutes = self.thermostat.next_change(week_time(now));
+        now + chrono::Duration::minutes(i64::from(minutes))
+            - chrono::Duration::seconds(i64::from(now.second()))
+    }
+
+    /// Drop the manual override once expired, return true if we did
+    pub fn check_manual(&mut self, now: DateTime<Local>) -> bool {
+        match self.manual {
+            Some(manual) if now >= manual.until => {
+                tracing::info!("{:?} is over", manual.mode);
+                self.manual = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remember a switch of the relay which did not go through `update`
+    pub fn record_switch(&mut self) {
+        self.guard.record(seconds(Local::now()));
+    }
+
+    /// Decide the relay at `now` from the last readings, return true if it switched
+    pub fn update(&mut self, now: DateTime<Local>) -> bool {
+        let h = self.hysteresis(now);
+        let (current, readings) = self.current(now);
+        self.source = sources::used(&readings);
+        let hot = if self.boosting() {
             true
+        } else if let Some(current) = current {
+            match self.strategy {
+                Strategy::Hysteresis => {
+                    control::regulate(self.target(now), self.band, self.thermostat.hot, current)
+                }
+                Strategy::Pid(gains) => {
+                    self.pid
+                        .step(&gains, self.target(now) - current, seconds(now));
+                    self.pid.relay(gains.period, &self.cycling, seconds(now))
+                }
+            }
+        } else if self.stale(now) {
+            self.frost_burst(now)
+        } else {
+            return false;
+        };
+        if hot == self.thermostat.hot {
+            return false;
+        }
+        if !self.guard.allow(&self.cycling, hot, seconds(now)) {
+            tracing::warn!(
+                "short cycle: not switching to {}, {} switches suppressed",
+                hot,
+                self.guard.suppressed
+            );
+            return false;
+        }
+        self.thermostat.hot = hot;
+        if let Some(current) = current {
+            tracing::info!(
+                "temperature ({}): {} / {} => chauffe: {}",
+                self.source.as_deref().unwrap_or_default(),
+                current,
+                h,
+                self.thermostat.hot
+            );
+            self.measure(now, current);
+        } else {
+            tracing::warn!("temperature unknown, frost protection => chauffe: {}", hot);
+        }
+        true
+    }
+
+    /// Learn the heating rate from each heating run, when the relay switches
+    fn measure(&mut self, now: DateTime<Local>, current: f64) {
+        if self.thermostat.hot {
+            self.heating_since = Some((now, current));
+        } else if let Some((since, start)) = self.heating_since.take() {
+            let hours = (now - since)
+                .to_std()
+                .map_or(0.0, |d| d.as_secs_f64() / 3600.0);
+            let rise = current - start;
+            if hours >= MIN_HEATING_RUN && rise > 0.0 {
+                let rate = (rise / hours).clamp(*HEATING_RATES.start(), *HEATING_RATES.end());
+                self.heating_rate = HEATING_RATE_WEIGHT
+                    .mul_add(rate, (1.0 - HEATING_RATE_WEIGHT) * self.heating_rate);
+                tracing::info!("heating rate: {:.2}°C/h", self.heating_rate);
+            }
+        }
+    }
+
+    /// When to leave away mode, to reach the schedule by `away_until`, as seen at `now`
+    pub fn return_at(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let until = self.away_until?;
+        let deficit = self.thermostat.scheduled(week_time(until))
+            - self.current(now).0.unwrap_or(self.thermostat.empty);
+        let rate = self
+            .heating_rate
+            .clamp(*HEATING_RATES.start(), *HEATING_RATES.end());
+        let lead = (deficit.max(0.0) / rate).min(MAX_LEAD);
+        let lead = chrono::Duration::from_std(Duration::from_secs_f64(lead * 3600.0)).ok()?;
+        Some(until - lead)
+    }
+
+    /// Leave away mode if it is time to heat for our return, return true if we did
+    pub fn check_return(&mut self, now: DateTime<Local>) -> bool {
+        match self.return_at(now) {
+            Some(at) if now >= at => {
+                tracing::info!("heating for our return at {:?}", self.away_until);
+                self.away_until = None;
+                self.thermostat.present = true;
+                true
+            }
+            _ => false,
         }
     }
 }
@@ -108,60 +682,768 @@ impl Thermostazv {
 pub struct TManager {
     thermostazv: Thermostazv,
     recv_cmd: TCmdReceiver,
+    recv_req: TReqReceiver,
     pub_state: TWatchSender,
+    get_status: SWatchReceiver,
     to_uart_send: Sender<Cmd>,
+    alert: AlertSender,
+    connected: Receiver<Identity>,
+    /// of the firmware we last connected to
+    capabilities: Capabilities,
+    mismatch_since: Option<Instant>,
+    setpoint: Option<Setpoint>,
     shutdown_receiver: tokio::sync::watch::Receiver<bool>,
 }
 
 impl TManager {
-    pub fn new(
+    #[allow(clippy::too_many_arguments)]
+    pub const fn new(
         thermostazv: Thermostazv,
         recv_cmd: TCmdReceiver,
+        recv_req: TReqReceiver,
         pub_state: TWatchSender,
+        get_status: SWatchReceiver,
         to_uart_send: Sender<Cmd>,
+        alert: AlertSender,
+        connected: Receiver<Identity>,
         shutdown_receiver: tokio::sync::watch::Receiver<bool>,
     ) -> Self {
         Self {
             thermostazv,
             recv_cmd,
+            recv_req,
             pub_state,
+            get_status,
             to_uart_send,
+            alert,
+            connected,
+            capabilities: Capabilities(0),
+            mismatch_since: None,
+            setpoint: None,
             shutdown_receiver,
         }
     }
 
+    fn publish(&self) -> ThermostazvResult {
+        self.thermostazv.save()?;
+        self.pub_state.send_if_modified(|old: &mut Thermostazv| {
+            if self.thermostazv == *old {
+                false
+            } else {
+                *old = self.thermostazv.clone();
+                true
+            }
+        });
+        Ok(())
+    }
+
+    /// Give the relay to the firmware in local mode, or take it back
+    async fn sync_mode(&mut self) -> ThermostazvResult {
+        if self.thermostazv.local && !self.capabilities.contains(Capabilities::LOCAL) {
+            self.alert
+                .send("firmware has no local mode".to_string())
+                .await?;
+            self.thermostazv.local = false;
+        }
+        if self.thermostazv.local {
+            self.setpoint = None;
+            self.push_setpoint().await?;
+            self.to_uart_send.send(Cmd::SetMode(Mode::Local)).await?;
+        } else {
+            if self.capabilities.contains(Capabilities::LOCAL) {
+                self.to_uart_send.send(Cmd::SetMode(Mode::Host)).await?;
+            }
+            self.to_uart_send
+                .send(Cmd::Set(Relay::from(self.thermostazv.thermostat.hot)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The serial link is up, maybe with a firmware which just reset: tell it everything again
+    async fn resync(&mut self, identity: Identity) -> ThermostazvResult {
+        self.capabilities = identity.capabilities;
+        self.mismatch_since = None;
+        self.sync_mode().await?;
+        self.publish()
+    }
+
+    /// Keep the firmware setpoint in line with our schedule in local mode
+    async fn push_setpoint(&mut self) -> ThermostazvResult {
+        let target = if self.thermostazv.boosting() {
+            *SETPOINTS.end()
+        } else {
+            self.thermostazv.target(Local::now())
+        };
+        let setpoint = self.thermostazv.firmware_setpoint(target);
+        if self.setpoint != Some(setpoint) {
+            self.to_uart_send.send(Cmd::SetLocal(setpoint)).await?;
+            self.setpoint = Some(setpoint);
+        }
+        Ok(())
+    }
+
+    /// Check that the firmware relay follows what we asked, and ask again if it does not
+    async fn check_relay(&mut self) -> ThermostazvResult {
+        let wanted = Relay::from(self.thermostazv.thermostat.hot);
+        let Cmd::Status(reported, _, mode) = *self.get_status.borrow() else {
+            return Ok(());
+        };
+        match (mode, self.thermostazv.local) {
+            (Mode::Failsafe, _) => {
+                self.alert
+                    .send("firmware went to failsafe, taking control back".to_string())
+                    .await?;
+                if self.thermostazv.local {
+                    self.sync_mode().await?;
+                } else {
+                    self.to_uart_send.send(Cmd::Set(wanted)).await?;
+                }
+                return Ok(());
+            }
+            (Mode::Local, true) => {
+                self.thermostazv.thermostat.hot = reported == Relay::Hot;
+                return self.publish();
+            }
+            (Mode::Local, false) | (Mode::Host, true) => {
+                self.alert
+                    .send(format!("firmware is in {mode:?} mode"))
+                    .await?;
+                return self.sync_mode().await;
+            }
+            (Mode::Host, false) => {}
+        }
+        if reported == wanted {
+            self.mismatch_since = None;
+            return Ok(());
+        }
+        let since = *self.mismatch_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= RELAY_MISMATCH_TIMEOUT {
+            self.alert
+                .send(format!("relay is {reported:?} instead of {wanted:?}"))
+                .await?;
+            self.to_uart_send.send(Cmd::Set(wanted)).await?;
+            self.mismatch_since = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, req: TCmd) -> ThermostazvResult {
+        match req {
+            TCmd::SetDay(val) => self.thermostazv.thermostat.day = val,
+            TCmd::SetNight(val) => self.thermostazv.thermostat.night = val,
+            TCmd::SetEmpty(val) => self.thermostazv.thermostat.empty = val,
+            TCmd::SetMorning(val) => self.thermostazv.thermostat.morning = val,
+            TCmd::SetEvening(val) => self.thermostazv.thermostat.evening = val,
+            TCmd::SetPresent(val) => {
+                self.thermostazv.away_until = None;
+                self.thermostazv.thermostat.present = val;
+            }
+            TCmd::SetAwayUntil(until) => {
+                self.thermostazv.away_until = until;
+                self.thermostazv.thermostat.present = until.is_none();
+                self.thermostazv.check_return(Local::now());
+            }
+            TCmd::SetHot(val) => {
+                if self.thermostazv.thermostat.hot != val {
+                    self.thermostazv.record_switch();
+                }
+                self.thermostazv.thermostat.hot = val;
+                self.to_uart_send.send(Cmd::Set(Relay::from(val))).await?;
+            }
+            TCmd::SetTarget(val) => {
+                *self
+                    .thermostazv
+                    .thermostat
+                    .target_mut(week_time(Local::now())) = val;
+            }
+            TCmd::SetSchedule(slots) => self.thermostazv.thermostat.schedule = *slots,
+            TCmd::Boost(minutes) => {
+                self.thermostazv.manual = Some(Manual {
+                    mode: ManualMode::Boost,
+                    until: Local::now() + chrono::Duration::minutes(i64::from(minutes)),
+                });
+                self.regulate().await?;
+            }
+            TCmd::Hold { target, until } => {
+                let now = Local::now();
+                self.thermostazv.manual = Some(Manual {
+                    mode: ManualMode::Hold(target),
+                    until: until.unwrap_or_else(|| self.thermostazv.next_change(now)),
+                });
+                self.regulate().await?;
+            }
+            TCmd::CancelManual => {
+                self.thermostazv.manual = None;
+                self.regulate().await?;
+            }
+            TCmd::SetCycling(cycling) => self.thermostazv.cycling = cycling,
+            TCmd::SetBand(val) => self.thermostazv.band = val,
+            TCmd::SetFrost(frost) => {
+                self.thermostazv.frost = frost;
+                self.regulate().await?;
+            }
+            TCmd::SetFusion(fusion) => {
+                self.thermostazv.fusion = fusion;
+                self.regulate().await?;
+            }
+            TCmd::SetCalibration {
+                sensor,
+                calibration,
+            } => {
+                self.thermostazv.calibration.insert(sensor, calibration);
+            }
+            TCmd::SetStrategy(strategy) => {
+                self.thermostazv.strategy = strategy;
+                self.thermostazv.pid = Pid::default();
+                self.regulate().await?;
+            }
+            TCmd::Current { source, value } => {
+                let (value, _) = self.thermostazv.calibrated(&source, value, 0.0);
+                self.thermostazv.read(&source, value, Local::now());
+                self.regulate().await?;
+            }
+            TCmd::SetLocal(val) => {
+                self.thermostazv.local = val;
+                self.sync_mode().await?;
+            }
+        }
+        if self.thermostazv.local {
+            self.push_setpoint().await?;
+        }
+        self.publish()
+    }
+
+    /// Run the thermostat on the last reading, in host mode, and tell when it changes source
+    async fn regulate(&mut self) -> ThermostazvResult {
+        if self.thermostazv.local {
+            return Ok(());
+        }
+        let source = self.thermostazv.source.clone();
+        if self.thermostazv.update(Local::now()) {
+            self.to_uart_send
+                .send(Cmd::Set(Relay::from(self.thermostazv.thermostat.hot)))
+                .await?;
+        }
+        if source != self.thermostazv.source {
+            self.alert
+                .send(format!(
+                    "temperature source: {} -> {}",
+                    source.as_deref().unwrap_or("none"),
+                    self.thermostazv.source.as_deref().unwrap_or("none")
+                ))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Feed the AHT20 reading of the firmware to its sources, and regulate on it
+    async fn read_status(&mut self) -> ThermostazvResult {
+        let Cmd::Status(_, SensorResult::Ok(sensor), _) = *self.get_status.borrow() else {
+            return Ok(());
+        };
+        let sources: std::vec::Vec<String> = self
+            .thermostazv
+            .aht20_sources()
+            .map(str::to_string)
+            .collect();
+        for source in sources {
+            let (celsius, _) = self
+                .thermostazv
+                .calibrated(&source, sensor.celsius(), sensor.rh());
+            self.thermostazv.read(&source, celsius, Local::now());
+        }
+        let (hot, source) = (
+            self.thermostazv.thermostat.hot,
+            self.thermostazv.source.clone(),
+        );
+        self.regulate().await?;
+        if hot != self.thermostazv.thermostat.hot || source != self.thermostazv.source {
+            self.publish()?;
+        }
+        Ok(())
+    }
+
+    /// Follow the clock: schedule changes, return from away mode, manual overrides
+    /// and switches delayed by the short cycle protection
+    async fn tick(&mut self) -> ThermostazvResult {
+        let now = Local::now();
+        let (hot, source) = (
+            self.thermostazv.thermostat.hot,
+            self.thermostazv.source.clone(),
+        );
+        let changed = self.thermostazv.check_return(now) | self.thermostazv.check_manual(now);
+        self.regulate().await?;
+        if changed || hot != self.thermostazv.thermostat.hot || source != self.thermostazv.source {
+            self.publish()?;
+        }
+        if self.thermostazv.local {
+            self.push_setpoint().await?;
+        }
+        Ok(())
+    }
+
     pub async fn manage(&mut self) -> ThermostazvResult {
+        // the sensors have `frost.stale` minutes from now to speak up
+        self.thermostazv.started = Some(Local::now());
+        let mut tick = tokio::time::interval(TICK);
         loop {
             tokio::select! {
                 _ = self.shutdown_receiver.changed() => return Ok(()),
+                _ = tick.tick() => self.tick().await?,
+                Ok(identity) = self.connected.recv() => self.resync(identity).await?,
+                Ok(()) = self.get_status.changed() => {
+                    self.read_status().await?;
+                    self.check_relay().await?;
+                }
                 req = self.recv_cmd.recv() => if let Ok(req) = req {
-                    match req {
-                        TCmd::SetDay(val) => self.thermostazv.day = val,
-                        TCmd::SetNight(val) => self.thermostazv.night = val,
-                        TCmd::SetEmpty(val) => self.thermostazv.empty = val,
-                        TCmd::SetMorning(val) => self.thermostazv.morning = val,
-                        TCmd::SetEvening(val) => self.thermostazv.evening = val,
-                        TCmd::SetPresent(val) => self.thermostazv.present = val,
-                        TCmd::SetHot(val) => self.thermostazv.hot = val,
-                        TCmd::Current(val) => {
-                            if self.thermostazv.update(val) {
-                                self.to_uart_send
-                                    .send(Cmd::Set(Relay::from(self.thermostazv.hot)))
-                                    .await?;
-                            }
-                        }
+                    match self.thermostazv.validate(&req) {
+                        Ok(()) => self.handle(req).await?,
+                        Err(e) => self.alert.send(e.to_string()).await?,
                     }
-                    self.thermostazv.save()?;
-                    self.pub_state.send_if_modified(|old: &mut Thermostazv| {
-                        if self.thermostazv == *old {
-                            false
-                        } else {
-                            *old = self.thermostazv.clone();
-                            true
+                },
+                req = self.recv_req.recv() => if let Ok(TReq { cmd, reply }) = req {
+                    let response = match self.thermostazv.validate(&cmd) {
+                        Ok(()) => {
+                            self.handle(cmd).await?;
+                            Response::State(Box::new(self.thermostazv.clone()))
                         }
-                    });
+                        Err(e) => Response::Error(e.to_string()),
+                    };
+                    if reply.send(response).is_err() {
+                        tracing::warn!("nobody waits for the response");
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::{Calibration, AHT20, SI7021};
+    use crate::sources::Method;
+
+    fn update(thermostazv: &mut Thermostazv, current: f64) -> bool {
+        let now = Local::now();
+        thermostazv.read(SI7021, current, now);
+        thermostazv.update(now)
+    }
+
+    /// What a save keeps: the config, without the runtime state
+    fn saved(thermostazv: &Thermostazv) -> Thermostazv {
+        Thermostazv {
+            guard: CycleGuard::default(),
+            pid: Pid::default(),
+            readings: BTreeMap::new(),
+            started: None,
+            source: None,
+            heating_since: None,
+            ..thermostazv.clone()
+        }
+    }
+
+    #[test]
+    fn config_roundtrip() {
+        let thermostazv = Thermostazv::default();
+        let toml = thermostazv.to_toml();
+        assert!(toml.is_ok(), "{toml:?}");
+        let toml = toml.unwrap_or_default();
+        assert!(toml.contains("day = 17.5"), "{toml}");
+        assert_eq!(toml::from_str(&toml).ok(), Some(thermostazv));
+    }
+
+    #[test]
+    fn config_without_local() {
+        let toml = "day = 18.0\nnight = 16.0\nempty = 10.0\nmorning = 7\nevening = 23\npresent = false\nhot = true\n";
+        let thermostazv: Result<Thermostazv, _> = toml::from_str(toml);
+        assert!(thermostazv.is_ok(), "{thermostazv:?}");
+        let thermostazv = thermostazv.unwrap_or_default();
+        assert_eq!(thermostazv.thermostat.morning, 7);
+        assert!(thermostazv.thermostat.hot);
+        assert!(!thermostazv.local);
+    }
+
+    #[test]
+    fn config_with_schedule() {
+        let toml = "day = 18.0\nnight = 16.0\nempty = 10.0\nmorning = 7\nevening = 23\npresent = true\nhot = false\nlocal = false\n\n[[schedule]]\ndays = [\"mon\", \"tue\"]\nstart = \"06:30\"\ntarget = 19.5\n";
+        let thermostazv: Result<Thermostazv, _> = toml::from_str(toml);
+        assert!(thermostazv.is_ok(), "{thermostazv:?}");
+        let thermostazv = thermostazv.unwrap_or_default();
+        assert_eq!(thermostazv.thermostat.schedule.len(), 1);
+        assert_eq!(thermostazv.thermostat.schedule[0].start.0, 6 * 60 + 30);
+        let out = thermostazv.to_toml();
+        assert!(out.is_ok(), "{out:?}");
+        let out = out.unwrap_or_default();
+        assert!(out.contains("start = \"06:30\""), "{out}");
+        assert_eq!(toml::from_str(&out).ok(), Some(thermostazv));
+    }
+
+    #[test]
+    fn config_with_away() {
+        let mut thermostazv = Thermostazv {
+            away_until: Some(Local::now()),
+            heating_rate: 2.0,
+            ..Thermostazv::default()
+        };
+        thermostazv.thermostat.present = false;
+        let toml = thermostazv.to_toml().unwrap_or_default();
+        assert!(toml.contains("away_until = "), "{toml}");
+        assert_eq!(toml::from_str(&toml).ok(), Some(thermostazv));
+    }
+
+    #[test]
+    fn away_return() {
+        let until = Local::now() + chrono::Duration::hours(24);
+        let mut thermostazv = Thermostazv {
+            away_until: Some(until),
+            heating_rate: 2.0,
+            ..Thermostazv::default()
+        };
+        thermostazv.read(SI7021, 10.0, Local::now());
+        thermostazv.thermostat.present = false;
+        thermostazv.thermostat.day = 18.0;
+        thermostazv.thermostat.night = 18.0;
+        let at = thermostazv.return_at(Local::now()).unwrap_or(until);
+        assert_eq!(until - at, chrono::Duration::hours(4));
+        assert!(!thermostazv.check_return(at - chrono::Duration::minutes(1)));
+        assert!(!thermostazv.thermostat.present);
+        assert!(thermostazv.check_return(at));
+        assert!(thermostazv.thermostat.present);
+        assert_eq!(thermostazv.away_until, None);
+    }
+
+    #[test]
+    fn heating_rate() {
+        let mut thermostazv = Thermostazv::default();
+        let start = Local::now();
+        thermostazv.thermostat.hot = true;
+        thermostazv.measure(start, 15.0);
+        thermostazv.thermostat.hot = false;
+        thermostazv.measure(start + chrono::Duration::minutes(30), 16.5);
+        let expected =
+            HEATING_RATE_WEIGHT.mul_add(3.0, (1.0 - HEATING_RATE_WEIGHT) * DEFAULT_HEATING_RATE);
+        assert!((thermostazv.heating_rate - expected).abs() < 1e-9);
+        // too short to be measured
+        thermostazv.thermostat.hot = true;
+        thermostazv.measure(start, 15.0);
+        thermostazv.thermostat.hot = false;
+        thermostazv.measure(start + chrono::Duration::minutes(5), 16.0);
+        assert!((thermostazv.heating_rate - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn manual() {
+        let now = Local::now();
+        let mut thermostazv = Thermostazv {
+            manual: Some(Manual {
+                mode: ManualMode::Hold(21.0),
+                until: now + chrono::Duration::minutes(30),
+            }),
+            cycling: Cycling {
+                min_on: 0,
+                min_off: 0,
+                max_per_hour: 0,
+            },
+            ..Thermostazv::default()
+        };
+        assert!((thermostazv.target(Local::now()) - 21.0).abs() < f64::EPSILON);
+        assert!(update(&mut thermostazv, 20.0));
+        assert!(thermostazv.thermostat.hot);
+        assert!(update(&mut thermostazv, 21.6));
+        let toml = thermostazv.to_toml().unwrap_or_default();
+        let back: Result<Thermostazv, _> = toml::from_str(&toml);
+        assert_eq!(
+            back.as_ref().ok(),
+            Some(&saved(&thermostazv)),
+            "{toml} {back:?}"
+        );
+
+        thermostazv.manual = Some(Manual {
+            mode: ManualMode::Boost,
+            until: now + chrono::Duration::minutes(30),
+        });
+        assert!(update(&mut thermostazv, 25.0));
+        assert!(thermostazv.thermostat.hot);
+        assert!(!thermostazv.check_manual(now));
+        assert!(thermostazv.check_manual(now + chrono::Duration::minutes(30)));
+        assert_eq!(thermostazv.manual, None);
+        assert!(update(&mut thermostazv, 25.0));
+        assert!(!thermostazv.thermostat.hot);
+    }
+
+    #[test]
+    fn short_cycling() {
+        let mut thermostazv = Thermostazv::default();
+        let target = thermostazv.target(Local::now());
+        assert!(update(&mut thermostazv, target - 1.0));
+        assert!(!update(&mut thermostazv, target + 1.0));
+        assert!(thermostazv.thermostat.hot);
+        assert_eq!(thermostazv.guard.suppressed, 1);
+    }
+
+    #[test]
+    fn pid_strategy() {
+        let mut thermostazv = Thermostazv {
+            strategy: Strategy::Pid(PidGains::default()),
+            cycling: Cycling {
+                min_on: 0,
+                min_off: 0,
+                max_per_hour: 0,
+            },
+            ..Thermostazv::default()
+        };
+        let target = thermostazv.target(Local::now());
+        assert!(update(&mut thermostazv, target - 1.0));
+        assert!(thermostazv.pid.duty > 0.0);
+        assert!((thermostazv.hysteresis(Local::now()) - target).abs() < f64::EPSILON);
+        let toml = thermostazv.to_toml().unwrap_or_default();
+        assert!(toml.contains("kind = \"pid\""), "{toml}");
+        let back: Result<Thermostazv, _> = toml::from_str(&toml);
+        assert_eq!(back.map(|t| t.strategy).ok(), Some(thermostazv.strategy));
+    }
+
+    #[test]
+    fn calibration() {
+        let mut thermostazv = Thermostazv::default();
+        let toml = "day = 18.0\nnight = 16.0\nempty = 10.0\nmorning = 7\nevening = 23\npresent = true\nhot = false\nband = 0.3\n\n[calibration.aht20]\ntemperature = { offset = -1.0, scale = 1.0 }\n";
+        let parsed: Result<Thermostazv, _> = toml::from_str(toml);
+        assert!(parsed.is_ok(), "{parsed:?}");
+        thermostazv = parsed.unwrap_or(thermostazv);
+        assert!((thermostazv.band - 0.3).abs() < f64::EPSILON);
+        let (celsius, rh) = thermostazv.calibrated(AHT20, 20.0, 50.0);
+        assert!((celsius - 19.0).abs() < f64::EPSILON);
+        assert!((rh - 50.0).abs() < f64::EPSILON);
+        assert_eq!(thermostazv.calibrated(SI7021, 20.0, 50.0), (20.0, 50.0));
+        // the firmware reads 1°C more than the truth
+        assert_eq!(
+            thermostazv.firmware_setpoint(19.0),
+            Setpoint::from_celsius(20.0, 0.3)
+        );
+        let toml = thermostazv.to_toml().unwrap_or_default();
+        assert_eq!(
+            toml::from_str(&toml).ok(),
+            Some(thermostazv.clone()),
+            "{toml}"
+        );
+        // the board AHT20 is known by the name of its source
+        let raw = SensorOk {
+            h: 1 << 19,
+            t: (1 << 19) + (1 << 18),
+        };
+        for source in &mut thermostazv.sources {
+            if source.input == Input::Aht20 {
+                source.name = "garage".to_string();
+            }
+        }
+        assert_eq!(thermostazv.calibrated_aht20(raw), (raw.celsius(), raw.rh()));
+        assert_eq!(
+            thermostazv.firmware_setpoint(19.0),
+            Setpoint::from_celsius(19.0, 0.3)
+        );
+        let correction = thermostazv.calibration.remove(AHT20).unwrap_or_default();
+        thermostazv
+            .calibration
+            .insert("garage".to_string(), correction);
+        let (celsius, _) = thermostazv.calibrated_aht20(raw);
+        assert!((celsius - (raw.celsius() - 1.0)).abs() < 1e-9);
+        assert_eq!(
+            thermostazv.firmware_setpoint(19.0),
+            Setpoint::from_celsius(20.0, 0.3)
+        );
+    }
+
+    #[test]
+    fn frost() {
+        let mut thermostazv = Thermostazv {
+            cycling: Cycling {
+                min_on: 0,
+                min_off: 0,
+                max_per_hour: 0,
+            },
+            ..Thermostazv::default()
+        };
+        let now = Local::now();
+        thermostazv.thermostat.present = false;
+        thermostazv.thermostat.empty = 3.0;
+        assert!((thermostazv.target(now) - 5.0).abs() < f64::EPSILON);
+        thermostazv.read(SI7021, 4.0, now);
+        assert!(thermostazv.update(now));
+        assert!(thermostazv.thermostat.hot);
+
+        // no news from the sensor for too long: bursts
+        let old = now - chrono::Duration::minutes(61);
+        thermostazv.readings.insert(SI7021.to_string(), (4.0, old));
+        thermostazv.started = Some(old);
+        assert!(thermostazv.stale(now));
+        thermostazv.thermostat.hot = !thermostazv.frost_burst(now);
+        assert!(thermostazv.update(now));
+        thermostazv.frost.burst = 0;
+        thermostazv.thermostat.hot = true;
+        assert!(thermostazv.update(now));
+        assert!(!thermostazv.thermostat.hot);
+    }
+
+    #[test]
+    fn fallback() {
+        let mut thermostazv = Thermostazv {
+            cycling: Cycling {
+                min_on: 0,
+                min_off: 0,
+                max_per_hour: 0,
+            },
+            ..Thermostazv::default()
+        };
+        let now = Local::now();
+        let old = now - chrono::Duration::minutes(61);
+        let target = thermostazv.target(now);
+        thermostazv.started = Some(now);
+        // waiting for the first reading
+        assert_eq!(thermostazv.current(now), (None, vec![]));
+        assert!(!thermostazv.stale(now));
+        assert!(!thermostazv.update(now));
+        thermostazv.read(AHT20, target - 1.0, now);
+        assert!(thermostazv.update(now));
+        assert_eq!(thermostazv.source.as_deref(), Some(AHT20));
+        thermostazv.read(SI7021, target + 1.0, now);
+        assert!(thermostazv.update(now));
+        assert_eq!(thermostazv.source.as_deref(), Some(SI7021));
+        assert!(!thermostazv.thermostat.hot);
+
+        // the Tasmota sensor went silent
+        thermostazv
+            .readings
+            .insert(SI7021.to_string(), (target + 1.0, old));
+        assert!(thermostazv.update(now));
+        assert_eq!(thermostazv.source.as_deref(), Some(AHT20));
+        assert!(thermostazv.thermostat.hot);
+
+        // and the AHT20 too
+        thermostazv
+            .readings
+            .insert(AHT20.to_string(), (target - 1.0, old));
+        thermostazv.started = Some(old);
+        thermostazv.update(now);
+        assert_eq!(thermostazv.source, None);
+        assert_eq!(thermostazv.thermostat.hot, thermostazv.frost_burst(now));
+
+        // the AHT20 first
+        thermostazv.read(SI7021, 20.0, now);
+        thermostazv.read(AHT20, 21.0, now);
+        let (current, readings) = thermostazv.current(now);
+        assert_eq!(current, Some(20.0));
+        assert_eq!(sources::used(&readings).as_deref(), Some(SI7021));
+        thermostazv.sources[0].priority = 2;
+        assert_eq!(thermostazv.current(now).0, Some(21.0));
+        thermostazv.fusion = Fusion {
+            method: Method::Mean,
+            outlier: 0.0,
+        };
+        let (current, readings) = thermostazv.current(now);
+        assert_eq!(current, Some(20.5));
+        assert_eq!(sources::used(&readings).as_deref(), Some("si7021+aht20"));
+    }
+
+    #[test]
+    fn validate() {
+        let mut thermostazv = Thermostazv::default();
+        assert!(thermostazv.validate(&TCmd::SetDay(19.0)).is_ok());
+        assert!(thermostazv.validate(&TCmd::SetDay(50.0)).is_err());
+        assert!(thermostazv.validate(&TCmd::SetTarget(4.0)).is_err());
+        assert!(thermostazv.validate(&TCmd::SetNight(f64::NAN)).is_err());
+        assert!(thermostazv.validate(&TCmd::SetMorning(7)).is_ok());
+        assert!(thermostazv.validate(&TCmd::SetMorning(22)).is_err());
+        assert!(thermostazv.validate(&TCmd::SetEvening(6)).is_err());
+        assert!(thermostazv.validate(&TCmd::SetEvening(25)).is_err());
+        let current = |source: &str, value| TCmd::Current {
+            source: source.to_string(),
+            value,
+        };
+        assert!(thermostazv.validate(&current(SI7021, 19.0)).is_ok());
+        assert!(thermostazv.validate(&current(SI7021, -60.0)).is_err());
+        assert!(thermostazv.validate(&current("dht22", 19.0)).is_err());
+        let fusion = |outlier| {
+            TCmd::SetFusion(Fusion {
+                method: Method::Median,
+                outlier,
+            })
+        };
+        assert!(thermostazv.validate(&fusion(1.5)).is_ok());
+        assert!(thermostazv.validate(&fusion(-1.0)).is_err());
+        assert!(thermostazv.validate(&TCmd::SetPresent(false)).is_ok());
+        assert!(thermostazv.validate(&TCmd::Boost(30)).is_ok());
+        let cycling = |max_per_hour| Cycling {
+            max_per_hour,
+            ..Cycling::default()
+        };
+        assert!(thermostazv.validate(&TCmd::SetCycling(cycling(12))).is_ok());
+        assert!(thermostazv
+            .validate(&TCmd::SetCycling(cycling(100)))
+            .is_err());
+        assert!(thermostazv.validate(&TCmd::Boost(0)).is_err());
+        assert!(thermostazv.validate(&TCmd::SetBand(0.2)).is_ok());
+        let frost = |floor, burst| {
+            TCmd::SetFrost(Frost {
+                floor,
+                burst,
+                ..Frost::default()
+            })
+        };
+        assert!(thermostazv.validate(&frost(7.0, 15)).is_ok());
+        assert!(thermostazv.validate(&frost(20.0, 15)).is_err());
+        assert!(thermostazv.validate(&frost(7.0, 60)).is_err());
+        assert!(thermostazv.validate(&TCmd::SetBand(0.0)).is_err());
+        let calibrate = |sensor: &str, scale| TCmd::SetCalibration {
+            sensor: sensor.to_string(),
+            calibration: calibration::Sensor {
+                temperature: Calibration { offset: 0.5, scale },
+                ..calibration::Sensor::default()
+            },
+        };
+        assert!(thermostazv.validate(&calibrate(AHT20, 1.0)).is_ok());
+        assert!(thermostazv.validate(&calibrate("dht22", 1.0)).is_err());
+        assert!(thermostazv.validate(&calibrate(SI7021, 0.0)).is_err());
+        let pid = |kp, period| {
+            TCmd::SetStrategy(Strategy::Pid(PidGains {
+                kp,
+                period,
+                ..PidGains::default()
+            }))
+        };
+        assert!(thermostazv.validate(&pid(1.0, 1200)).is_ok());
+        assert!(thermostazv.validate(&pid(-1.0, 1200)).is_err());
+        assert!(thermostazv.validate(&pid(1.0, 10)).is_err());
+        // more than 6 switches per hour
+        assert!(thermostazv.validate(&pid(1.0, 900)).is_err());
+        thermostazv.strategy = Strategy::Pid(PidGains::default());
+        assert!(thermostazv.validate(&TCmd::SetCycling(cycling(12))).is_ok());
+        assert!(thermostazv.validate(&TCmd::SetCycling(cycling(4))).is_err());
+        let cmd = serde_json::from_str(r#"{"SetStrategy": {"kind": "hysteresis"}}"#);
+        assert!(
+            matches!(cmd, Ok(TCmd::SetStrategy(Strategy::Hysteresis))),
+            "{cmd:?}"
+        );
+        let hold = |target, until| TCmd::Hold { target, until };
+        assert!(thermostazv.validate(&hold(19.0, None)).is_ok());
+        assert!(thermostazv.validate(&hold(45.0, None)).is_err());
+        let past = Some(Local::now() - chrono::Duration::minutes(1));
+        assert!(thermostazv.validate(&hold(19.0, past)).is_err());
+        let schedule = r#"{"SetSchedule": [{"days": ["sat"], "start": "08:15", "target": 19.0}]}"#;
+        let cmd = serde_json::from_str(schedule).unwrap_or(TCmd::SetDay(f64::NAN));
+        assert!(thermostazv.validate(&cmd).is_ok(), "{cmd:?}");
+        let schedule = r#"{"SetSchedule": [{"days": [], "start": "08:15", "target": 19.0}]}"#;
+        let cmd = serde_json::from_str(schedule).unwrap_or(TCmd::SetDay(19.0));
+        assert!(thermostazv.validate(&cmd).is_err(), "{cmd:?}");
+        let schedule = r#"{"SetSchedule": [{"days": ["sat"], "start": "24:15", "target": 19.0}]}"#;
+        assert!(serde_json::from_str::<TCmd>(schedule).is_err());
+    }
+
+    #[test]
+    fn json_api() {
+        let cmd: Result<TCmd, _> = serde_json::from_str(r#"{"SetDay": 18.5}"#);
+        assert!(matches!(cmd, Ok(TCmd::SetDay(v)) if (v - 18.5).abs() < f64::EPSILON));
+        let response = serde_json::to_string(&Response::Error("nope".to_string()));
+        assert_eq!(response.ok().as_deref(), Some(r#"{"error":"nope"}"#));
+    }
+}
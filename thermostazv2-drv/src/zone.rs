@@ -0,0 +1,362 @@
+//! Heating zones: a board, the thermostat driving it and its MQTT subtree
+
+use crate::err::{ThermostazvError, ThermostazvResult};
+use crate::hass::{discovery, AVAILABILITY, PRESET, TARGET};
+use crate::sources::Source;
+use crate::status::smanager;
+use crate::tasks::{heartbeat, influx, mqtt_publish, mqtt_receive, serial_link};
+use crate::thermostazv::{config_file, TManager, Thermostazv};
+use async_channel::{unbounded, Receiver};
+use futures::future::{select_all, try_join_all};
+use rumqttc::{AsyncClient, Publish, QoS};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use thermostazv2_lib::{Cmd, Failsafe, FailsafeAction, Mode, Relay, SensorErr, SensorResult};
+use tokio::sync::watch;
+use tokio::task::{self, JoinHandle};
+use tokio::time::sleep;
+
+/// The zone of a single board daemon, its topics and config file predate the zones
+pub const DEFAULT_ZONE: &str = "thermostazv";
+
+/// Delays between two restarts of a failing zone, doubling from min to max
+const RESTART_MIN: Duration = Duration::from_secs(5);
+const RESTART_MAX: Duration = Duration::from_secs(40);
+
+type Tasks = Vec<JoinHandle<ThermostazvResult>>;
+
+/// MQTT topic `leaf` of `zone`
+pub fn topic(zone: &str, leaf: &str) -> String {
+    format!("/azv/{zone}/{leaf}")
+}
+
+/// A board and its thermostat, configured in their own file, under `/azv/{name}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Zone {
+    pub name: String,
+    /// serial port of the board, found by its USB IDs with the `udev` feature if not given
+    pub port: Option<String>,
+    /// USB serial number of the board
+    pub serial: Option<String>,
+}
+
+impl FromStr for Zone {
+    type Err = ThermostazvError;
+
+    /// `name`, `name=SERIAL` or `name=/dev/ttyACM0`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, board) = s.split_once('=').map_or((s, None), |(n, b)| (n, Some(b)));
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(ThermostazvError::Zone(format!("bad name {name:?}")));
+        }
+        let (port, serial) = match board {
+            Some(port) if port.starts_with('/') => (Some(port.to_string()), None),
+            Some("") | None => (None, None),
+            Some(serial) => (None, Some(serial.to_string())),
+        };
+        Ok(Self {
+            name: name.to_string(),
+            port,
+            serial,
+        })
+    }
+}
+
+/// Zones need distinct names, none taking the config file of the default zone,
+/// and once there are several, a way to tell their boards apart
+pub fn check(zones: &[Zone]) -> Result<(), ThermostazvError> {
+    for (i, zone) in zones.iter().enumerate() {
+        if zone.name != DEFAULT_ZONE && config_file(&zone.name) == config_file(DEFAULT_ZONE) {
+            return Err(ThermostazvError::Zone(format!("{} is reserved", zone.name)));
+        }
+        if zones[..i].iter().any(|z| z.name == zone.name) {
+            return Err(ThermostazvError::Zone(format!("{} twice", zone.name)));
+        }
+        if zones.len() > 1 && zone.port.is_none() && zone.serial.is_none() {
+            return Err(ThermostazvError::Zone(format!(
+                "{} needs a serial number or a port",
+                zone.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Options shared by all the zones
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub baud: u32,
+    pub failsafe_timeout: u16,
+    pub failsafe_target: Option<f64>,
+    pub text_log: bool,
+    pub infl_buck: String,
+}
+
+/// Load the config of `zone` and spawn its tasks, which stop with `stop`:
+/// the serial link and the controller, then the helpers which handle their own failures
+#[allow(clippy::too_many_lines)]
+async fn start(
+    zone: &Zone,
+    settings: &Settings,
+    client: &AsyncClient,
+    influx_client: &influxdb2::Client,
+    from_mqtt_receive: &Receiver<Publish>,
+    stop: &watch::Receiver<bool>,
+) -> anyhow::Result<(Tasks, Tasks)> {
+    let name = &zone.name;
+    let thermostazv = Thermostazv::new(name)?;
+    let (thermostazv_cmd_send, thermostazv_cmd_receive) = unbounded();
+    let (thermostazv_req_send, thermostazv_req_receive) = unbounded();
+    let (thermostazv_watch_send, thermostazv_watch_receive) = watch::channel(thermostazv.clone());
+
+    let status = Cmd::Status(
+        Relay::Cold,
+        SensorResult::Err(SensorErr::Uninitialized),
+        Mode::Host,
+    );
+    let (status_cmd_send, status_cmd_receive) = unbounded();
+    let (status_watch_send, status_watch_receive) = watch::channel(status);
+
+    let (to_uart_send, to_uart_receive) = unbounded();
+    let failsafe = Failsafe {
+        timeout: settings.failsafe_timeout,
+        action: FailsafeAction::Regulate(
+            thermostazv.firmware_setpoint(
+                settings
+                    .failsafe_target
+                    .map_or(thermostazv.frost.floor, |t| t.max(thermostazv.frost.floor)),
+            ),
+        ),
+    };
+    let (connected_send, connected_receive) = unbounded();
+    let (alert_send, alert_receive) = unbounded();
+    let (response_send, response_receive) = unbounded();
+    let (to_mqtt_send, to_mqtt_receive) = unbounded();
+
+    for leaf in [
+        "cmd".to_string(),
+        "set".to_string(),
+        "presence".to_string(),
+        format!("{TARGET}/set"),
+        format!("{PRESET}/set"),
+    ] {
+        client
+            .subscribe(topic(name, &leaf), QoS::AtMostOnce)
+            .await?;
+    }
+    for source in thermostazv.sources.iter().filter_map(Source::topic) {
+        client.subscribe(source, QoS::AtMostOnce).await?;
+    }
+    client
+        .publish(topic(name, "log"), QoS::AtLeastOnce, false, "Hi !")
+        .await?;
+    client
+        .publish(topic(name, AVAILABILITY), QoS::AtLeastOnce, true, "Online")
+        .await?;
+    discovery(client, name).await?;
+
+    let mut vital = vec![task::spawn(serial_link(
+        zone.port.clone(),
+        zone.serial.clone(),
+        settings.baud,
+        failsafe,
+        to_uart_send.clone(),
+        to_uart_receive,
+        status_cmd_send,
+        to_mqtt_send.clone(),
+        connected_send,
+        alert_send.clone(),
+        stop.clone(),
+    ))];
+
+    let mut helpers = vec![task::spawn(heartbeat(to_uart_send.clone(), stop.clone()))];
+
+    helpers.push(task::spawn(mqtt_receive(
+        to_uart_send.clone(),
+        from_mqtt_receive.clone(),
+        thermostazv_cmd_send,
+        thermostazv_req_send,
+        status_watch_receive,
+        to_mqtt_send,
+        response_send,
+        thermostazv.sources.clone(),
+        name.clone(),
+        stop.clone(),
+    )));
+
+    helpers.push(task::spawn(mqtt_publish(
+        to_mqtt_receive,
+        alert_receive,
+        response_receive,
+        thermostazv_watch_receive,
+        status_watch_send.subscribe(),
+        client.clone(),
+        settings.text_log,
+        name.clone(),
+        stop.clone(),
+    )));
+
+    let influx_client = influx_client.clone();
+    let thermostazv_watch_receive = thermostazv_watch_send.subscribe();
+    let status_watch_receive = status_watch_send.subscribe();
+    let infl_buck = settings.infl_buck.clone();
+    let device = name.clone();
+    let shutdown_receiver = stop.clone();
+    helpers.push(task::spawn(async move {
+        influx(
+            influx_client,
+            thermostazv_watch_receive,
+            status_watch_receive,
+            &infl_buck,
+            &device,
+            shutdown_receiver,
+        )
+        .await
+    }));
+
+    let mut tmanager = TManager::new(
+        thermostazv,
+        thermostazv_cmd_receive,
+        thermostazv_req_receive,
+        thermostazv_watch_send,
+        status_watch_send.subscribe(),
+        to_uart_send,
+        alert_send,
+        connected_receive,
+        stop.clone(),
+    );
+    vital.push(task::spawn(async move { tmanager.manage().await }));
+    helpers.push(task::spawn(smanager(
+        status_cmd_receive,
+        status_watch_send,
+        stop.clone(),
+    )));
+    Ok((vital, helpers))
+}
+
+/// Run `zone` until shutdown, restarting all its tasks when its serial link or its controller
+/// ends, so that their failures stay out of the other zones
+pub async fn supervise(
+    zone: Zone,
+    settings: Settings,
+    client: AsyncClient,
+    influx_client: influxdb2::Client,
+    from_mqtt_receive: Receiver<Publish>,
+    mut shutdown_receiver: watch::Receiver<bool>,
+) -> ThermostazvResult {
+    let mut delay = RESTART_MIN;
+    loop {
+        let (stop_send, stop_receive) = watch::channel(false);
+        let started = Instant::now();
+        // commands sent while the zone was down are stale
+        while from_mqtt_receive.try_recv().is_ok() {}
+        let error = match start(
+            &zone,
+            &settings,
+            &client,
+            &influx_client,
+            &from_mqtt_receive,
+            &stop_receive,
+        )
+        .await
+        {
+            Ok((vital, helpers)) => {
+                let mut running = select_all(vital);
+                tokio::select! {
+                    _ = shutdown_receiver.changed() => {
+                        stop_send.send_replace(true);
+                        try_join_all(running.into_inner()).await?;
+                        try_join_all(helpers).await?;
+                        return Ok(());
+                    },
+                    (ended, _, rest) = &mut running => {
+                        for task in rest.into_iter().chain(helpers) {
+                            task.abort();
+                        }
+                        match ended {
+                            Ok(Ok(())) => "a task ended".to_string(),
+                            Ok(Err(e)) => e.to_string(),
+                            Err(e) => e.to_string(),
+                        }
+                    },
+                }
+            }
+            Err(e) => e.to_string(),
+        };
+        if started.elapsed() > RESTART_MAX {
+            delay = RESTART_MIN;
+        }
+        tracing::error!("zone {} down, restart in {:?}: {}", zone.name, delay, error);
+        client
+            .publish(
+                topic(&zone.name, AVAILABILITY),
+                QoS::AtLeastOnce,
+                true,
+                "Offline",
+            )
+            .await?;
+        client
+            .publish(
+                topic(&zone.name, "alert"),
+                QoS::AtLeastOnce,
+                false,
+                format!("zone down: {error}"),
+            )
+            .await?;
+        tokio::select! {
+            _ = shutdown_receiver.changed() => return Ok(()),
+            () = sleep(delay) => {},
+        }
+        delay = (delay * 2).min(RESTART_MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(s: &str) -> Option<Zone> {
+        s.parse().ok()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            zone("garage"),
+            Some(Zone {
+                name: "garage".to_string(),
+                port: None,
+                serial: None,
+            })
+        );
+        assert_eq!(
+            zone("salon=0A1B2C").and_then(|z| z.serial),
+            Some("0A1B2C".to_string())
+        );
+        assert_eq!(
+            zone("salon=/dev/ttyACM1").and_then(|z| z.port),
+            Some("/dev/ttyACM1".to_string())
+        );
+        assert_eq!(zone("salon=").map(|z| z.serial), Some(None));
+        assert_eq!(zone("=0A1B2C"), None);
+        assert_eq!(zone("salle de bain"), None);
+        assert_eq!(zone("a/b"), None);
+    }
+
+    #[test]
+    fn zones() {
+        let zones = |list: &[&str]| -> Vec<Zone> { list.iter().filter_map(|s| zone(s)).collect() };
+        assert!(check(&zones(&["garage"])).is_ok());
+        assert!(check(&zones(&["garage=A", "salon=/dev/ttyACM1"])).is_ok());
+        assert!(check(&zones(&["garage=A", "garage=B"])).is_err());
+        assert!(check(&zones(&["garage=A", "salon"])).is_err());
+        assert!(check(&zones(&["thermostazv"])).is_ok());
+        assert!(check(&zones(&["config"])).is_err());
+        assert!(check(&zones(&["thermostazv2"])).is_ok());
+        assert_eq!(topic("salon", "cmd"), "/azv/salon/cmd");
+    }
+}
@@ -0,0 +1,573 @@
+//! Heating control, shared by the driver and the firmware
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Default half width of the hysteresis band, in °C
+pub const BAND: f64 = 0.5;
+
+/// Threshold of a two-point controller with a ±`band` hysteresis around `target`
+#[must_use]
+pub fn threshold(target: f64, band: f64, hot: bool) -> f64 {
+    target + if hot { band } else { -band }
+}
+
+/// Whether the heater should be on at temperature `current`, knowing whether it already is
+#[must_use]
+pub fn regulate(target: f64, band: f64, hot: bool, current: f64) -> bool {
+    current <= threshold(target, band, hot)
+}
+
+/// Minutes in a day
+pub const DAY: u32 = 24 * 60;
+
+/// Minutes in a week
+pub const WEEK: u32 = 7 * DAY;
+
+/// Maximum number of slots in a weekly schedule
+pub const SLOTS: usize = 32;
+
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/// A moment in the week, to the minute
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct WeekTime {
+    /// 0 is Monday
+    pub weekday: u32,
+    /// minutes since midnight
+    pub minute: u32,
+}
+
+impl WeekTime {
+    #[must_use]
+    pub const fn new(weekday: u32, hour: u32, minute: u32) -> Self {
+        Self {
+            weekday,
+            minute: hour * 60 + minute,
+        }
+    }
+
+    #[must_use]
+    pub const fn hour(&self) -> u32 {
+        self.minute / 60
+    }
+
+    /// Minutes since Monday midnight
+    #[must_use]
+    pub const fn of_week(&self) -> u32 {
+        self.weekday * DAY + self.minute
+    }
+}
+
+/// Minutes since midnight, written "HH:MM"
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct HourMinute(pub u32);
+
+impl Serialize for HourMinute {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:02}:{:02}", self.0 / 60, self.0 % 60))
+    }
+}
+
+impl<'de> Deserialize<'de> for HourMinute {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = HourMinute;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.write_str("a time of day as \"HH:MM\"")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<HourMinute, E> {
+                let parsed = v
+                    .split_once(':')
+                    .and_then(|(h, m)| Some((h.parse::<u32>().ok()?, m.parse::<u32>().ok()?)));
+                match parsed {
+                    Some((h, m)) if h < 24 && m < 60 => Ok(HourMinute(h * 60 + m)),
+                    _ => Err(E::invalid_value(serde::de::Unexpected::Str(v), &self)),
+                }
+            }
+        }
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+/// From `start` on `days`, keep `target` until the next slot
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Slot {
+    pub days: Vec<Weekday, 7>,
+    pub start: HourMinute,
+    pub target: f64,
+}
+
+/// Seconds in an hour
+const HOUR: u64 = 3600;
+
+/// Gains of a PID controller whose output is the share of `period` the relay is on
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub struct PidGains {
+    /// per °C
+    pub kp: f64,
+    /// per °C·h
+    pub ki: f64,
+    /// per °C/h
+    pub kd: f64,
+    /// length of a relay cycle, in seconds, which must fit in the `Cycling` limits
+    pub period: u32,
+}
+
+impl Default for PidGains {
+    fn default() -> Self {
+        Self {
+            kp: 0.5,
+            ki: 0.2,
+            kd: 0.0,
+            period: 1200,
+        }
+    }
+}
+
+/// How to drive the relay
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Strategy {
+    /// two-point control with a hysteresis of ± the configured band
+    #[default]
+    Hysteresis,
+    /// PID with a time-proportional relay output
+    Pid(PidGains),
+}
+
+/// Hours from `from` to `to`, given in seconds
+fn hours(from: u64, to: u64) -> f64 {
+    f64::from(u32::try_from(to.saturating_sub(from)).unwrap_or(u32::MAX)) / 3600.0
+}
+
+/// State of a PID controller
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct Pid {
+    /// integral of the error, in °C·h
+    integral: f64,
+    /// last error and when it was seen, in seconds
+    last: Option<(f64, u64)>,
+    /// share of the cycle the relay should be on, 0 to 1
+    pub duty: f64,
+    /// start of the current relay cycle, in seconds, and its duty cycle
+    cycle: Option<(u64, f64)>,
+}
+
+impl Pid {
+    /// Update the duty cycle from the `error`, target minus current, at `now`, in seconds
+    #[allow(clippy::suboptimal_flops)] // no `mul_add` without std
+    pub fn step(&mut self, gains: &PidGains, error: f64, now: u64) -> f64 {
+        let (integral, derivative) = match self.last {
+            Some((last, at)) if now > at => {
+                let dt = hours(at, now);
+                (error * dt + self.integral, (error - last) / dt)
+            }
+            _ => (self.integral, 0.0),
+        };
+        let p = gains.kp * error;
+        let d = gains.kd * derivative;
+        let output = gains.ki * integral + p + d;
+        // anti-windup: stop integrating when saturated, unless it gets us out
+        if (0.0..=1.0).contains(&output)
+            || (output > 1.0 && error < 0.0)
+            || (output < 0.0 && error > 0.0)
+        {
+            self.integral = integral;
+        }
+        self.last = Some((error, now));
+        self.duty = (gains.ki * self.integral + p + d).clamp(0.0, 1.0);
+        self.duty
+    }
+
+    /// Whether the relay is on at `now`, in seconds, within cycles of `period` seconds,
+    /// with runs and pauses too short for the `limits` rounded away
+    pub fn relay(&mut self, period: u32, limits: &Cycling, now: u64) -> bool {
+        let (start, duty) = match self.cycle {
+            Some((start, duty)) if now < start + u64::from(period) => (start, duty),
+            _ => {
+                let min_on = f64::from(limits.min_on) / f64::from(period);
+                let min_off = f64::from(limits.min_off) / f64::from(period);
+                let duty = if self.duty < min_on {
+                    0.0
+                } else if 1.0 - self.duty < min_off {
+                    1.0
+                } else {
+                    self.duty
+                };
+                self.cycle = Some((now, duty));
+                (now, duty)
+            }
+        };
+        hours(start, now) < duty * hours(0, u64::from(period))
+    }
+}
+
+/// Most switches `CycleGuard` remembers, so most switches per hour it can enforce
+pub const MAX_SWITCHES: usize = 60;
+
+/// Limits protecting the relay from short cycles
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Cycling {
+    /// shortest heating run, in seconds
+    pub min_on: u32,
+    /// shortest pause between heating runs, in seconds
+    pub min_off: u32,
+    /// most switches in an hour, 0 for no limit
+    pub max_per_hour: u32,
+}
+
+impl Cycling {
+    /// Whether relay cycles of `period` seconds, a run and a pause each, get through
+    #[must_use]
+    pub fn fits(&self, period: u32) -> bool {
+        let per_hour = (2 * HOUR).checked_div(u64::from(period));
+        u64::from(self.min_on) + u64::from(self.min_off) <= u64::from(period)
+            && (self.max_per_hour == 0
+                || matches!(per_hour, Some(n) if n <= u64::from(self.max_per_hour)))
+    }
+}
+
+impl Default for Cycling {
+    fn default() -> Self {
+        Self {
+            min_on: 300,
+            min_off: 300,
+            max_per_hour: 6,
+        }
+    }
+}
+
+/// Recent switches of the relay, to enforce `Cycling`
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct CycleGuard {
+    /// when the relay switched, in seconds
+    switches: Vec<u64, MAX_SWITCHES>,
+    /// switches refused so far
+    pub suppressed: u32,
+}
+
+impl CycleGuard {
+    fn forget(&mut self, now: u64) {
+        self.switches.retain(|&t| now < t + HOUR);
+    }
+
+    /// Remember a switch at `now`, in seconds
+    pub fn record(&mut self, now: u64) {
+        self.forget(now);
+        if self.switches.is_full() {
+            self.switches.remove(0);
+        }
+        self.switches.push(now).ok();
+    }
+
+    /// Whether the relay may switch to `hot` at `now`, in seconds, and remember it if so
+    pub fn allow(&mut self, limits: &Cycling, hot: bool, now: u64) -> bool {
+        self.forget(now);
+        let min = if hot { limits.min_off } else { limits.min_on };
+        let too_soon = matches!(self.switches.last(), Some(&last) if now < last + u64::from(min));
+        let max = usize::try_from(limits.max_per_hour).unwrap_or(usize::MAX);
+        let too_many = max > 0 && self.switches.len() >= max;
+        if too_soon || too_many {
+            self.suppressed = self.suppressed.saturating_add(1);
+            false
+        } else {
+            self.record(now);
+            true
+        }
+    }
+}
+
+/// Day / night / empty house thermostat, with an optional weekly schedule
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Thermostat {
+    pub day: f64,
+    pub night: f64,
+    pub empty: f64,
+    pub morning: u32,
+    pub evening: u32,
+    pub present: bool,
+    pub hot: bool,
+    /// replaces `day`, `night`, `morning` and `evening` when not empty
+    #[serde(default)]
+    pub schedule: Vec<Slot, SLOTS>,
+}
+
+impl Default for Thermostat {
+    fn default() -> Self {
+        Self {
+            day: 17.5,
+            night: 17.0,
+            empty: 10.0,
+            morning: 6,
+            evening: 22,
+            present: true,
+            hot: false,
+            schedule: Vec::new(),
+        }
+    }
+}
+
+impl Thermostat {
+    /// Index in `schedule` of the slot in use at `at`
+    #[must_use]
+    pub fn slot(&self, at: WeekTime) -> Option<usize> {
+        let now = at.of_week();
+        let mut best: Option<(u32, usize)> = None;
+        for (i, slot) in self.schedule.iter().enumerate() {
+            for day in &slot.days {
+                let start = *day as u32 * DAY + slot.start.0;
+                let age = (now + WEEK - start) % WEEK;
+                if !matches!(best, Some((a, _)) if a <= age) {
+                    best = Some((age, i));
+                }
+            }
+        }
+        best.map(|(_, i)| i)
+    }
+
+    /// The setpoint of the schedule at `at`, as if we were present
+    #[must_use]
+    pub fn scheduled(&self, at: WeekTime) -> f64 {
+        self.slot(at).map_or_else(
+            || {
+                if self.morning <= at.hour() && at.hour() < self.evening {
+                    self.day
+                } else {
+                    self.night
+                }
+            },
+            |i| self.schedule[i].target,
+        )
+    }
+
+    /// Minutes from `at` to the next change of the schedule
+    #[must_use]
+    pub fn next_change(&self, at: WeekTime) -> u32 {
+        let now = at.of_week();
+        let mut next = WEEK;
+        let mut consider = |start: u32| {
+            let wait = (start + WEEK - now) % WEEK;
+            if wait > 0 && wait < next {
+                next = wait;
+            }
+        };
+        if self.schedule.is_empty() {
+            for day in 0..7 {
+                consider(day * DAY + self.morning * 60);
+                consider(day * DAY + self.evening * 60);
+            }
+        } else {
+            for slot in &self.schedule {
+                for day in &slot.days {
+                    consider(*day as u32 * DAY + slot.start.0);
+                }
+            }
+        }
+        next
+    }
+
+    #[must_use]
+    pub fn target(&self, at: WeekTime) -> f64 {
+        if self.present {
+            self.scheduled(at)
+        } else {
+            self.empty
+        }
+    }
+
+    /// The setpoint in use at `at`, to change it
+    pub fn target_mut(&mut self, at: WeekTime) -> &mut f64 {
+        if !self.present {
+            &mut self.empty
+        } else if let Some(i) = self.slot(at) {
+            &mut self.schedule[i].target
+        } else if self.morning <= at.hour() && at.hour() < self.evening {
+            &mut self.day
+        } else {
+            &mut self.night
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn noon() -> WeekTime {
+        WeekTime::new(2, 12, 0)
+    }
+
+    #[test]
+    fn target() {
+        let mut thermostat = Thermostat::default();
+        let at = |hour| WeekTime::new(0, hour, 0);
+        assert!((thermostat.target(at(5)) - thermostat.night).abs() < f64::EPSILON);
+        assert!((thermostat.target(at(6)) - thermostat.day).abs() < f64::EPSILON);
+        assert!((thermostat.target(at(21)) - thermostat.day).abs() < f64::EPSILON);
+        assert!((thermostat.target(at(22)) - thermostat.night).abs() < f64::EPSILON);
+        *thermostat.target_mut(at(23)) = 16.0;
+        assert!((thermostat.night - 16.0).abs() < f64::EPSILON);
+        thermostat.present = false;
+        assert!((thermostat.target(noon()) - thermostat.empty).abs() < f64::EPSILON);
+        *thermostat.target_mut(noon()) = 12.0;
+        assert!((thermostat.empty - 12.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn schedule() {
+        let slot = |days: &[Weekday], h: u32, m: u32, target: f64| Slot {
+            days: Vec::from_slice(days).unwrap_or_default(),
+            start: HourMinute(h * 60 + m),
+            target,
+        };
+        let weekdays = [
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+        ];
+        let mut thermostat = Thermostat::default();
+        for slot in [
+            slot(&weekdays, 6, 30, 19.0),
+            slot(&weekdays, 12, 0, 17.0),
+            slot(&weekdays, 13, 30, 19.0),
+            slot(&weekdays, 22, 15, 16.0),
+            slot(&[Weekday::Sat, Weekday::Sun], 8, 0, 20.0),
+            slot(&[Weekday::Sat, Weekday::Sun], 23, 0, 16.0),
+        ] {
+            assert!(thermostat.schedule.push(slot).is_ok());
+        }
+        let target = |weekday, h, m| thermostat.target(WeekTime::new(weekday, h, m));
+        assert!((target(0, 6, 29) - 16.0).abs() < f64::EPSILON);
+        assert!((target(0, 6, 30) - 19.0).abs() < f64::EPSILON);
+        assert!((target(2, 12, 45) - 17.0).abs() < f64::EPSILON);
+        assert!((target(4, 23, 0) - 16.0).abs() < f64::EPSILON);
+        assert!((target(5, 7, 59) - 16.0).abs() < f64::EPSILON);
+        assert!((target(6, 12, 0) - 20.0).abs() < f64::EPSILON);
+        // wraps from Sunday night to Monday morning
+        assert!((target(0, 0, 10) - 16.0).abs() < f64::EPSILON);
+        *thermostat.target_mut(WeekTime::new(3, 12, 10)) = 18.0;
+        assert_eq!(thermostat.slot(WeekTime::new(0, 12, 0)), Some(1));
+        assert!((thermostat.schedule[1].target - 18.0).abs() < f64::EPSILON);
+        assert_eq!(thermostat.next_change(WeekTime::new(0, 12, 0)), 90);
+        assert_eq!(
+            thermostat.next_change(WeekTime::new(4, 22, 15)),
+            9 * 60 + 45
+        );
+    }
+
+    #[test]
+    fn next_change() {
+        let thermostat = Thermostat::default();
+        assert_eq!(thermostat.next_change(WeekTime::new(0, 5, 0)), 60);
+        assert_eq!(thermostat.next_change(WeekTime::new(0, 6, 0)), 16 * 60);
+        assert_eq!(
+            thermostat.next_change(WeekTime::new(6, 23, 30)),
+            6 * 60 + 30
+        );
+    }
+
+    #[test]
+    fn short_cycles() {
+        let limits = Cycling {
+            min_on: 300,
+            min_off: 600,
+            max_per_hour: 3,
+        };
+        let mut guard = CycleGuard::default();
+        assert!(guard.allow(&limits, true, 1000));
+        assert!(!guard.allow(&limits, false, 1299));
+        assert!(guard.allow(&limits, false, 1300));
+        assert!(!guard.allow(&limits, true, 1899));
+        assert!(guard.allow(&limits, true, 1900));
+        // fourth switch in the hour
+        assert!(!guard.allow(&limits, false, 4599));
+        assert!(guard.allow(&limits, false, 4600));
+        assert_eq!(guard.suppressed, 3);
+    }
+
+    #[test]
+    fn pid() {
+        let gains = PidGains {
+            kp: 0.5,
+            ki: 0.5,
+            kd: 0.0,
+            period: 600,
+        };
+        let mut pid = Pid::default();
+        assert!((pid.step(&gains, 1.0, 0) - 0.5).abs() < 1e-9);
+        // one hour at 1°C under the target
+        assert!((pid.step(&gains, 1.0, 3600) - 1.0).abs() < 1e-9);
+        // saturated: the integral stops growing
+        for hour in 2..10 {
+            assert!((pid.step(&gains, 1.0, hour * 3600) - 1.0).abs() < 1e-9);
+        }
+        assert!((pid.integral - 1.0).abs() < 1e-9);
+        // so it comes back as soon as we overshoot
+        assert!(pid.step(&gains, -0.5, 10 * 3600 + 60) < 0.3);
+    }
+
+    #[test]
+    fn time_proportional() {
+        let free = Cycling {
+            min_on: 0,
+            min_off: 0,
+            max_per_hour: 0,
+        };
+        let mut pid = Pid {
+            duty: 0.25,
+            ..Pid::default()
+        };
+        let on: usize = (0..600)
+            .filter(|&s| pid.relay(600, &free, 1000 + s))
+            .count();
+        assert_eq!(on, 150);
+        pid.duty = 1.0;
+        assert!(pid.relay(600, &free, 1600));
+        assert!(pid.relay(600, &free, 2199));
+        pid.duty = 0.0;
+        assert!(!pid.relay(600, &free, 2200));
+
+        // a 150 s run is too short for the default limits
+        let limits = Cycling::default();
+        pid.duty = 0.25;
+        assert!(!pid.relay(600, &limits, 2800));
+        pid.duty = 0.5;
+        let on: usize = (0..600)
+            .filter(|&s| pid.relay(600, &limits, 3400 + s))
+            .count();
+        assert_eq!(on, 300);
+        pid.duty = 0.75;
+        assert!(pid.relay(600, &limits, 4599));
+        assert!(pid.relay(600, &limits, 5198));
+        assert!(limits.fits(1200));
+        assert!(!limits.fits(900));
+        assert!(!limits.fits(0));
+        assert!(free.fits(60));
+    }
+
+    #[test]
+    fn hysteresis() {
+        let (target, band) = (17.5, 0.3);
+        assert!(regulate(target, band, false, 17.1));
+        assert!(!regulate(target, band, false, 17.3));
+        assert!(regulate(target, band, true, 17.7));
+        assert!(!regulate(target, band, true, 17.9));
+        assert!((threshold(target, band, true) - 17.8).abs() < 1e-9);
+        assert!((threshold(target, band, false) - 17.2).abs() < 1e-9);
+    }
+}
@@ -1,11 +1,14 @@
 #![no_std]
 #![feature(error_in_core)]
 
-use heapless::Vec;
+use core::ops::BitOr;
+use heapless::{String, Vec};
 use postcard::experimental::max_size::MaxSize;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+pub mod control;
+
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum TError {
     #[error("postcard error {0}")]
@@ -13,6 +16,13 @@ pub enum TError {
 
     #[error("sensor error {0:?}")]
     Sensor(SensorErr),
+
+    #[error("cobs error")]
+    Cobs,
+
+    /// with the `seq` the frame seems to carry, to `Nack` it
+    #[error("checksum mismatch")]
+    Crc(Option<Seq>),
 }
 
 /// Sensor: AHT20
@@ -66,25 +76,249 @@ impl From<bool> for Relay {
     }
 }
 
+/// Target temperature and hysteresis band, in hundredths of °C
+#[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Setpoint {
+    pub target: i16,
+    pub band: u16,
+}
+
+/// Hundredths of `value`, rounded half away from zero by the truncating cast, no `round` without std
+fn hundredths(value: f64) -> f64 {
+    let hundredths = value * 100.0;
+    if hundredths < 0.0 {
+        hundredths - 0.5
+    } else {
+        hundredths + 0.5
+    }
+}
+
+impl Setpoint {
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn from_celsius(target: f64, band: f64) -> Self {
+        Self {
+            target: hundredths(target).clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16,
+            band: hundredths(band).clamp(0.0, f64::from(u16::MAX)) as u16,
+        }
+    }
+
+    #[must_use]
+    pub fn target(&self) -> f64 {
+        f64::from(self.target) / 100.0
+    }
+
+    #[must_use]
+    pub fn band(&self) -> f64 {
+        f64::from(self.band) / 100.0
+    }
+
+    #[must_use]
+    pub fn regulate(&self, hot: bool, current: f64) -> bool {
+        control::regulate(self.target(), self.band(), hot, current)
+    }
+}
+
+/// What the firmware does when the host goes silent
+#[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum FailsafeAction {
+    Cold,
+    Regulate(Setpoint),
+}
+
+#[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Failsafe {
+    /// Seconds without any frame from the host before the firmware takes over, 0 to disable
+    pub timeout: u16,
+    pub action: FailsafeAction,
+}
+
+impl Default for Failsafe {
+    fn default() -> Self {
+        Self {
+            timeout: 60,
+            action: FailsafeAction::Cold,
+        }
+    }
+}
+
+/// Shortest run or pause of the relay when the firmware regulates by itself, in seconds,
+/// as the default `control::Cycling` of the host which is not there to enforce it
+pub const MIN_HOLD: u16 = 300;
+
+/// Who drives the relay
 #[repr(u8)]
 #[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Mode {
+    Host,
+    /// The host went silent, until its next `Set`
+    Failsafe,
+    /// The firmware regulates around its own `Setpoint`, the host only supervises
+    Local,
+}
+
+/// USB vendor and product IDs of the board
+pub const USB_VID: u16 = 0x6565;
+pub const USB_PID: u16 = 0x0003;
+
+/// Length of the USB serial number of a board
+pub const SERIAL_SIZE: usize = 24;
+
+/// USB serial number of a board: the 96 bits unique ID of its STM32, in hex
+#[must_use]
+pub fn serial_number(uid: &[u8; 12]) -> [u8; SERIAL_SIZE] {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let mut serial = [0; SERIAL_SIZE];
+    for (i, byte) in uid.iter().enumerate() {
+        serial[2 * i] = HEX[usize::from(byte >> 4)];
+        serial[2 * i + 1] = HEX[usize::from(byte & 0xf)];
+    }
+    serial
+}
+
+/// Wire protocol revision, to bump on every incompatible change to `Frame` or `Cmd`.
+/// New variants must be appended at the end of `Cmd`, so that existing ones keep their index.
+pub const PROTOCOL_VERSION: u16 = 5;
+
+/// Oldest protocol revision the driver can still talk to
+pub const PROTOCOL_MIN_VERSION: u16 = 4;
+
+/// Bitmask of the optional features of a firmware
+#[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const RELAY: Self = Self(1 << 0);
+    pub const AHT20: Self = Self(1 << 1);
+    pub const FAILSAFE: Self = Self(1 << 2);
+    pub const LOCAL: Self = Self(1 << 3);
+
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+pub type FirmwareVersion = String<16>;
+
+/// Device identity, sent by the firmware in answer to `Cmd::Hello`
+#[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Clone)]
+pub struct Identity {
+    pub protocol: u16,
+    pub firmware: FirmwareVersion,
+    pub capabilities: Capabilities,
+}
+
+pub type Seq = u16;
+
+/// Why the firmware refused a command
+#[repr(u8)]
+#[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Copy, Clone)]
+pub enum NackReason {
+    Unexpected,
+    Busy,
+    /// Not allowed in the current `Mode`
+    Mode,
+    /// The frame failed its checksum, send it again
+    Corrupted,
+}
+
+#[repr(u8)]
+#[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Clone)]
 pub enum Cmd {
     Get,
     Ping,
     Pong,
     Set(Relay),
-    Status(Relay, SensorResult),
+    Status(Relay, SensorResult, Mode),
+    Hello(u16),
+    HelloAck(Identity),
+    Ack(Seq),
+    Nack(Seq, NackReason),
+    SetFailsafe(Failsafe),
+    SetLocal(Setpoint),
+    SetMode(Mode),
 }
 
-pub type TVec = Vec<u8, { Cmd::POSTCARD_MAX_SIZE + 2 }>;
-
 impl Cmd {
+    /// Protocol revision which introduced the command
+    #[must_use]
+    pub const fn since(&self) -> u16 {
+        match self {
+            Self::Get
+            | Self::Ping
+            | Self::Pong
+            | Self::Set(_)
+            | Self::Status(..)
+            | Self::Hello(_)
+            | Self::HelloAck(_) => 1,
+            Self::Ack(_) | Self::Nack(..) => 3,
+            Self::SetFailsafe(_) | Self::SetLocal(_) | Self::SetMode(_) => 4,
+        }
+    }
+
+    /// What the firmware needs to carry out the command,
+    /// the firmwares of a same protocol revision may not all have the latest features
+    #[must_use]
+    pub const fn needs(&self) -> Capabilities {
+        match self {
+            Self::SetFailsafe(_) => Capabilities::FAILSAFE,
+            Self::SetLocal(_) | Self::SetMode(_) => Capabilities::LOCAL,
+            _ => Capabilities(0),
+        }
+    }
+}
+
+/// What goes on the wire: every frame received by the firmware is answered by
+/// an `Ack` or a `Nack` carrying its `seq`.
+#[derive(Deserialize, Serialize, MaxSize, Debug, Eq, PartialEq, Clone)]
+pub struct Frame {
+    pub seq: Seq,
+    pub cmd: Cmd,
+}
+
+/// CRC-16 appended to the postcard payload, inside the COBS frame
+const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+const CRC_SIZE: usize = 2;
+
+/// Maximum size of a COBS encoded frame, including the trailing `0` delimiter
+pub const FRAME_SIZE: usize = Frame::POSTCARD_MAX_SIZE + CRC_SIZE + 2;
+
+pub type TVec = Vec<u8, FRAME_SIZE>;
+
+impl Frame {
     pub fn to_vec(&self) -> Result<TVec, TError> {
-        postcard::to_vec_cobs(&self).map_err(TError::Postcard)
+        let mut raw = [0; Self::POSTCARD_MAX_SIZE + CRC_SIZE];
+        let len = postcard::to_slice(self, &mut raw)
+            .map_err(TError::Postcard)?
+            .len();
+        let crc = CRC.checksum(&raw[..len]).to_le_bytes();
+        raw[len..len + CRC_SIZE].copy_from_slice(&crc);
+
+        let mut frame = [0; FRAME_SIZE];
+        let used = cobs::encode(&raw[..len + CRC_SIZE], &mut frame);
+        TVec::from_slice(&frame[..=used]).map_err(|()| TError::Cobs)
     }
 
     pub fn from_vec(value: &mut [u8]) -> Result<Self, TError> {
-        postcard::from_bytes_cobs(value).map_err(TError::Postcard)
+        let len = cobs::decode_in_place(value).map_err(|()| TError::Cobs)?;
+        if len < CRC_SIZE {
+            return Err(TError::Crc(None));
+        }
+        let (payload, crc) = value[..len].split_at(len - CRC_SIZE);
+        if CRC.checksum(payload).to_le_bytes() != crc {
+            let seq = postcard::take_from_bytes::<Seq>(payload).ok();
+            return Err(TError::Crc(seq.map(|(seq, _)| seq)));
+        }
+        postcard::from_bytes(payload).map_err(TError::Postcard)
     }
 }
 
@@ -94,26 +328,122 @@ mod tests {
     extern crate std;
 
     #[test]
-    fn cmd_to_vec_to_cmd() {
-        let cmd_in = Cmd::Status(
-            Relay::Hot,
-            SensorResult::Ok(SensorOk {
-                h: u32::MAX,
-                t: u32::MAX,
-            }),
-        );
+    fn frame_to_vec_to_frame() {
+        let frame_in = Frame {
+            seq: Seq::MAX,
+            cmd: Cmd::Status(
+                Relay::Hot,
+                SensorResult::Ok(SensorOk {
+                    h: u32::MAX,
+                    t: u32::MAX,
+                }),
+                Mode::Failsafe,
+            ),
+        };
 
-        std::dbg!(cmd_in);
-        let data = cmd_in.to_vec();
+        std::dbg!(&frame_in);
+        let data = frame_in.to_vec();
         assert!(data.is_ok(), "data is not ok: {data:?}");
         let mut data = data.unwrap();
         std::dbg!(&data);
         std::println!("data len: {}", data.len());
-        std::println!("max len: {}", Cmd::POSTCARD_MAX_SIZE);
-        let cmd_out = Cmd::from_vec(data.as_mut());
-        assert!(cmd_out.is_ok(), "cmd_out is not ok: {cmd_out:?}");
-        let cmd_out = cmd_out.unwrap();
-        std::dbg!(cmd_out);
-        assert_eq!(cmd_out, cmd_in);
+        std::println!("max len: {}", Frame::POSTCARD_MAX_SIZE);
+        let frame_out = Frame::from_vec(data.as_mut());
+        assert!(frame_out.is_ok(), "frame_out is not ok: {frame_out:?}");
+        let frame_out = frame_out.unwrap();
+        std::dbg!(&frame_out);
+        assert_eq!(frame_out, frame_in);
+    }
+
+    #[test]
+    fn hello_ack_to_vec_to_frame() {
+        let frame_in = Frame {
+            seq: 0,
+            cmd: Cmd::HelloAck(Identity {
+                protocol: PROTOCOL_VERSION,
+                firmware: FirmwareVersion::from("0.1.0"),
+                capabilities: Capabilities::RELAY | Capabilities::AHT20,
+            }),
+        };
+
+        let mut data = frame_in.to_vec().unwrap_or_default();
+        assert_eq!(Frame::from_vec(data.as_mut()), Ok(frame_in));
+    }
+
+    #[test]
+    fn capabilities() {
+        let caps = Capabilities::RELAY | Capabilities::AHT20;
+        assert!(caps.contains(Capabilities::RELAY));
+        assert!(caps.contains(Capabilities::AHT20));
+        assert!(!Capabilities::RELAY.contains(caps));
+        assert!(caps.contains(Capabilities::default()));
+        assert!(caps.contains(Cmd::Set(Relay::Hot).needs()));
+        assert!(!caps.contains(Cmd::SetMode(Mode::Local).needs()));
+        assert!(Cmd::SetLocal(Setpoint::from_celsius(17.0, 0.5)).since() <= PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn bit_flips_are_rejected() {
+        let frame_in = Frame {
+            seq: 42,
+            cmd: Cmd::Set(Relay::Cold),
+        };
+        let data = frame_in.to_vec().unwrap_or_default();
+        assert!(!data.is_empty());
+
+        for i in 0..data.len() - 1 {
+            for bit in 0..8 {
+                let mut corrupted = data.clone();
+                corrupted[i] ^= 1 << bit;
+                let frame_out = Frame::from_vec(corrupted.as_mut());
+                assert!(frame_out.is_err(), "byte {i} bit {bit} gave {frame_out:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let frame = Frame {
+            seq: 1,
+            cmd: Cmd::Set(Relay::Cold),
+        };
+        let mut data = frame.to_vec().unwrap_or_default();
+        // COBS overhead, then seq, then the variant index
+        data[2] ^= 1;
+        assert_eq!(Frame::from_vec(data.as_mut()), Err(TError::Crc(Some(1))));
+        assert_eq!(Frame::from_vec(&mut [1, 0]), Err(TError::Crc(None)));
+    }
+
+    #[test]
+    fn serial() {
+        let uid = [
+            0x00, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x0f, 0xff, 0x01,
+        ];
+        let serial = serial_number(&uid);
+        assert_eq!(
+            core::str::from_utf8(&serial),
+            Ok("00123456789ABCDEF00FFF01")
+        );
+    }
+
+    #[test]
+    fn setpoint() {
+        let setpoint = Setpoint::from_celsius(19.5, 0.5);
+        assert_eq!(
+            setpoint,
+            Setpoint {
+                target: 1950,
+                band: 50
+            }
+        );
+        assert!((setpoint.target() - 19.5).abs() < f64::EPSILON);
+        assert!((setpoint.band() - 0.5).abs() < f64::EPSILON);
+        // 2028.9999999999998 hundredths
+        assert_eq!(Setpoint::from_celsius(20.29, 0.07).target, 2029);
+        assert_eq!(Setpoint::from_celsius(20.29, 0.07).band, 7);
+        assert_eq!(Setpoint::from_celsius(-0.29, -1.0).target, -29);
+        assert_eq!(Setpoint::from_celsius(-0.29, -1.0).band, 0);
+        assert_eq!(Setpoint::from_celsius(1000.0, 1000.0).target, i16::MAX);
+        assert_eq!(Setpoint::from_celsius(1000.0, 1000.0).band, u16::MAX);
     }
 }
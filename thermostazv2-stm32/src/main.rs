@@ -14,14 +14,18 @@ mod app {
     use rtt_target::{rprintln, rtt_init_print};
     use stm32f1xx_hal::gpio::PinState;
     use stm32f1xx_hal::gpio::{Alternate, OpenDrain, Output, PushPull, PB6, PB7, PB8, PC13};
-    use stm32f1xx_hal::i2c::{BlockingI2c, DutyCycle, Mode};
+    use stm32f1xx_hal::i2c::{BlockingI2c, DutyCycle, Mode as I2cMode};
     use stm32f1xx_hal::pac::I2C1;
     use stm32f1xx_hal::pac::TIM2;
     use stm32f1xx_hal::prelude::*;
     use stm32f1xx_hal::timer::MonoTimerUs;
     use stm32f1xx_hal::usb::{Peripheral, UsbBus, UsbBusType};
     use stm32f1xx_hal::watchdog::IndependentWatchdog;
-    use thermostazv2_lib::{Cmd, Relay, SensorErr, SensorOk, SensorResult, TVec};
+    use thermostazv2_lib::{
+        Capabilities, Cmd, Failsafe, FailsafeAction, FirmwareVersion, Frame, Identity, Mode,
+        NackReason, Relay, SensorErr, SensorOk, SensorResult, Seq, Setpoint, TError, TVec,
+        MIN_HOLD, PROTOCOL_VERSION, SERIAL_SIZE, USB_PID, USB_VID,
+    };
     use usb_device::prelude::*;
 
     type I2c = BlockingI2c<I2C1, (PB6<Alternate<OpenDrain>>, PB7<Alternate<OpenDrain>>)>;
@@ -32,6 +36,13 @@ mod app {
         relay: PB8<Output<PushPull>>,
         sensor: SensorResult,
         serial: usbd_serial::SerialPort<'static, UsbBusType>,
+        mode: Mode,
+        failsafe: Failsafe,
+        local: Setpoint,
+        /// seconds since the last frame from the host
+        silence: u16,
+        /// seconds since the last switch of the relay
+        held: u16,
     }
 
     #[local]
@@ -51,6 +62,7 @@ mod app {
         rtt_init_print!();
         rprintln!("init start");
         static mut USB_BUS: Option<usb_device::bus::UsbBusAllocator<UsbBusType>> = None;
+        static mut SERIAL: [u8; SERIAL_SIZE] = [0; SERIAL_SIZE];
 
         let mut flash = cx.device.FLASH.constrain();
         let rcc = cx.device.RCC.constrain();
@@ -94,14 +106,22 @@ mod app {
         #[allow(clippy::unwrap_used)]
         let serial = usbd_serial::SerialPort::new(unsafe { USB_BUS.as_ref().unwrap() });
 
+        // 96 bits unique device ID, see RM0008 30.2
+        let uid = unsafe { core::ptr::read_volatile(0x1FFF_F7E8 as *const [u8; 12]) };
+        let serial_number = unsafe {
+            SERIAL = thermostazv2_lib::serial_number(&uid);
+            core::str::from_utf8(&SERIAL).unwrap_or("0001")
+        };
+        rprintln!("serial number {}", serial_number);
+
         #[allow(clippy::unwrap_used)]
         let usb_dev = UsbDeviceBuilder::new(
             unsafe { USB_BUS.as_ref().unwrap() },
-            UsbVidPid(0x6565, 0x0003),
+            UsbVidPid(USB_VID, USB_PID),
         )
         .manufacturer("Nim")
         .product("thermostazv2")
-        .serial_number("0001")
+        .serial_number(serial_number)
         .device_class(usbd_serial::USB_CLASS_CDC)
         .build();
 
@@ -113,7 +133,7 @@ mod app {
             cx.device.I2C1,
             (scl, sda),
             &mut afio.mapr,
-            Mode::Fast {
+            I2cMode::Fast {
                 frequency: 400.kHz(),
                 duty_cycle: DutyCycle::Ratio16to9,
             },
@@ -156,6 +176,11 @@ mod app {
                 relay,
                 sensor,
                 serial,
+                mode: Mode::Host,
+                failsafe: Failsafe::default(),
+                local: Setpoint::from_celsius(17.0, 0.5),
+                silence: 0,
+                held: 0,
             },
             Local {
                 led,
@@ -168,9 +193,11 @@ mod app {
         )
     }
 
-    #[task(capacity = 3, local = [data, usb_dev], shared = [serial])]
+    #[task(capacity = 3, local = [data, usb_dev], shared = [serial, silence, mode])]
     fn recv(cx: recv::Context) {
         let mut serial = cx.shared.serial;
+        let mut silence = cx.shared.silence;
+        let mut mode = cx.shared.mode;
         let usb_dev = cx.local.usb_dev;
         let data = cx.local.data;
 
@@ -185,20 +212,60 @@ mod app {
                 #[allow(clippy::unwrap_used)]
                 data.push(buf[0]).unwrap();
                 if buf[0] == 0 {
-                    Cmd::from_vec(data).map_or_else(
-                        |_| {
-                            rprintln!("Couldn't decode {:?}", data);
+                    Frame::from_vec(data).map_or_else(
+                        |e| {
+                            rprintln!("Couldn't decode {:?}: {}", data, e);
+                            // the host resends at once instead of waiting for the ack
+                            if let TError::Crc(Some(seq)) = e {
+                                let nack = Cmd::Nack(seq, NackReason::Corrupted);
+                                if let Err(nack) = send::spawn(nack) {
+                                    rprintln!("couldn't send {:?}", nack);
+                                }
+                            }
                         },
-                        |cmd| {
-                            rprintln!("received {:?}", cmd);
-
-                            #[allow(clippy::unwrap_used)]
-                            match cmd {
-                                Cmd::Get => send_status::spawn().unwrap(),
-                                Cmd::Set(r) => set_relay::spawn(r).unwrap(),
-                                Cmd::Ping => send::spawn(Cmd::Pong).unwrap(),
-                                Cmd::Status(_, _) => rprintln!("wrong cmd received: {:?}", cmd),
-                                Cmd::Pong => rprintln!("pong"),
+                        |frame| {
+                            rprintln!("received {:?}", frame);
+                            silence.lock(|silence| *silence = 0);
+
+                            let Frame { seq, cmd } = frame;
+                            let res = match cmd {
+                                Cmd::Get => send_status::spawn().map_err(|_| NackReason::Busy),
+                                Cmd::Set(r) => {
+                                    if mode.lock(|mode| *mode == Mode::Local) {
+                                        Err(NackReason::Mode)
+                                    } else {
+                                        set_relay::spawn(r).map_err(|_| NackReason::Busy)
+                                    }
+                                }
+                                Cmd::Ping => send::spawn(Cmd::Pong).map_err(|_| NackReason::Busy),
+                                Cmd::Hello(v) => {
+                                    rprintln!("host speaks protocol {}", v);
+                                    send::spawn(Cmd::HelloAck(identity()))
+                                        .map_err(|_| NackReason::Busy)
+                                }
+                                Cmd::Status(..)
+                                | Cmd::HelloAck(_)
+                                | Cmd::Ack(_)
+                                | Cmd::Nack(_, _) => {
+                                    rprintln!("wrong cmd received: {:?}", cmd);
+                                    Err(NackReason::Unexpected)
+                                }
+                                Cmd::Pong => {
+                                    rprintln!("pong");
+                                    Ok(())
+                                }
+                                Cmd::SetFailsafe(f) => {
+                                    set_failsafe::spawn(f).map_err(|_| NackReason::Busy)
+                                }
+                                Cmd::SetLocal(s) => {
+                                    set_local::spawn(s).map_err(|_| NackReason::Busy)
+                                }
+                                Cmd::SetMode(Mode::Failsafe) => Err(NackReason::Mode),
+                                Cmd::SetMode(m) => set_mode::spawn(m).map_err(|_| NackReason::Busy),
+                            };
+                            let ack = res.map_or_else(|r| Cmd::Nack(seq, r), |()| Cmd::Ack(seq));
+                            if let Err(ack) = send::spawn(ack) {
+                                rprintln!("couldn't send {:?}", ack);
                             }
                         },
                     );
@@ -212,6 +279,19 @@ mod app {
         });
     }
 
+    fn identity() -> Identity {
+        let mut firmware = FirmwareVersion::new();
+        firmware.push_str(env!("CARGO_PKG_VERSION")).ok();
+        Identity {
+            protocol: PROTOCOL_VERSION,
+            firmware,
+            capabilities: Capabilities::RELAY
+                | Capabilities::AHT20
+                | Capabilities::FAILSAFE
+                | Capabilities::LOCAL,
+        }
+    }
+
     #[task(binds = USB_HP_CAN_TX)]
     fn usb_tx(_cx: usb_tx::Context) {
         #[allow(clippy::unwrap_used)]
@@ -224,7 +304,7 @@ mod app {
         recv::spawn().unwrap();
     }
 
-    #[task(local = [led, state, iwdg])]
+    #[task(local = [led, state, iwdg], shared = [silence, held, failsafe, mode])]
     fn blink(cx: blink::Context) {
         cx.local.iwdg.feed();
         if *cx.local.state {
@@ -235,20 +315,105 @@ mod app {
             *cx.local.state = true;
         }
 
+        let silence = cx.shared.silence;
+        let held = cx.shared.held;
+        let failsafe = cx.shared.failsafe;
+        let mode = cx.shared.mode;
+        (silence, held, failsafe, mode).lock(|silence, held, failsafe, mode| {
+            *silence = silence.saturating_add(1);
+            *held = held.saturating_add(1);
+            if failsafe.timeout != 0 && *silence >= failsafe.timeout && *mode == Mode::Host {
+                rprintln!("host silent for {}s, entering failsafe", silence);
+                *mode = Mode::Failsafe;
+                regulate::spawn().ok();
+            }
+        });
+
         #[allow(clippy::unwrap_used)]
         blink::spawn_after(1.secs()).unwrap();
         //start_read::spawn().unwrap();
     }
 
-    #[task(shared = [relay])]
+    #[task(shared = [relay, held, mode])]
     fn set_relay(cx: set_relay::Context, state: Relay) {
-        let mut relay = cx.shared.relay;
-        relay.lock(|relay| match state {
-            Relay::Cold => relay.set_low(),
-            Relay::Hot => relay.set_high(),
+        let relay = cx.shared.relay;
+        let held = cx.shared.held;
+        let mode = cx.shared.mode;
+        (relay, held, mode).lock(|relay, held, mode| {
+            if *mode == Mode::Failsafe {
+                rprintln!("host is back, leaving failsafe");
+                *mode = Mode::Host;
+            }
+            if Relay::from(relay.is_set_high()) != state {
+                *held = 0;
+            }
+            match state {
+                Relay::Cold => relay.set_low(),
+                Relay::Hot => relay.set_high(),
+            }
         });
     }
 
+    #[task(shared = [failsafe])]
+    fn set_failsafe(cx: set_failsafe::Context, new: Failsafe) {
+        let mut failsafe = cx.shared.failsafe;
+        failsafe.lock(|failsafe| *failsafe = new);
+    }
+
+    #[task(shared = [local])]
+    fn set_local(cx: set_local::Context, setpoint: Setpoint) {
+        let mut local = cx.shared.local;
+        local.lock(|local| *local = setpoint);
+        regulate::spawn().ok();
+    }
+
+    #[task(shared = [mode])]
+    fn set_mode(cx: set_mode::Context, new: Mode) {
+        let mut mode = cx.shared.mode;
+        mode.lock(|mode| {
+            rprintln!("mode {:?} -> {:?}", mode, new);
+            *mode = new;
+        });
+        regulate::spawn().ok();
+    }
+
+    /// Drive the relay by ourselves while in failsafe or local mode, holding it `MIN_HOLD`
+    /// seconds at least, unless there is nothing to regulate on
+    #[task(shared = [relay, held, sensor, mode, failsafe, local])]
+    fn regulate(cx: regulate::Context) {
+        let relay = cx.shared.relay;
+        let held = cx.shared.held;
+        let sensor = cx.shared.sensor;
+        let mode = cx.shared.mode;
+        let failsafe = cx.shared.failsafe;
+        let local = cx.shared.local;
+        (relay, held, sensor, mode, failsafe, local).lock(
+            |relay, held, sensor, mode, failsafe, local| {
+                let setpoint = match (*mode, failsafe.action) {
+                    (Mode::Host, _) => return,
+                    (Mode::Local, _) => Some(*local),
+                    (Mode::Failsafe, FailsafeAction::Regulate(setpoint)) => Some(setpoint),
+                    (Mode::Failsafe, FailsafeAction::Cold) => None,
+                };
+                let (hot, forced) = match (setpoint, sensor) {
+                    (Some(setpoint), SensorResult::Ok(s)) => {
+                        (setpoint.regulate(relay.is_set_high(), s.celsius()), false)
+                    }
+                    _ => (false, true),
+                };
+                if hot == relay.is_set_high() || (*held < MIN_HOLD && !forced) {
+                    return;
+                }
+                *held = 0;
+                if hot {
+                    relay.set_high();
+                } else {
+                    relay.set_low();
+                }
+            },
+        );
+    }
+
     #[task(shared = [aht20])]
     fn calibrate(cx: calibrate::Context) {
         let mut aht20 = cx.shared.aht20;
@@ -321,29 +486,38 @@ mod app {
             };
             *sensor = msg;
         });
-        #[allow(clippy::unwrap_used)]
-        send_status::spawn().unwrap();
+        regulate::spawn().ok();
+        // a pending one sends the fresh reading as well
+        send_status::spawn().ok();
         #[allow(clippy::unwrap_used)]
         start_read::spawn_after(5.secs()).unwrap();
     }
 
-    #[task(shared = [relay, sensor])]
+    #[task(shared = [relay, sensor, mode])]
     fn send_status(cx: send_status::Context) {
         let sensor = cx.shared.sensor;
         let relay = cx.shared.relay;
-        (sensor, relay).lock(|sensor, relay| {
-            let cmd = Cmd::Status(Relay::from(relay.is_set_high()), *sensor);
-            #[allow(clippy::unwrap_used)]
-            send::spawn(cmd).unwrap();
+        let mode = cx.shared.mode;
+        (sensor, relay, mode).lock(|sensor, relay, mode| {
+            let cmd = Cmd::Status(Relay::from(relay.is_set_high()), *sensor, *mode);
+            if let Err(cmd) = send::spawn(cmd) {
+                rprintln!("couldn't send {:?}", cmd);
+            }
         });
     }
 
-    #[task(shared = [serial])]
+    /// Room for a burst: every frame of the host may queue an answer and its ack
+    #[task(capacity = 16, local = [seq: Seq = 0], shared = [serial])]
     fn send(cx: send::Context, cmd: Cmd) {
-        rprintln!("send {:?}", cmd);
+        *cx.local.seq = cx.local.seq.wrapping_add(1);
+        let frame = Frame {
+            seq: *cx.local.seq,
+            cmd,
+        };
+        rprintln!("send {:?}", frame);
         let mut serial = cx.shared.serial;
-        cmd.to_vec().map_or_else(
-            |e| rprintln!("{:?} to_vec err: {}", cmd, e),
+        frame.to_vec().map_or_else(
+            |e| rprintln!("{:?} to_vec err: {}", frame, e),
             |vec| {
                 serial.lock(|serial| serial.write(vec.as_slice()).ok());
             },